@@ -1,10 +1,24 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::format;
 use std::fs::File;
 use std::io::{self, Write};
 use std::path::Path;
 
-use crate::ast::{ASTNode, AST};
-use crate::symbol_table::{self, SymbolTable};
+use crate::ast::{asm_references, walk, ASTNode, Spanned, AST};
+use crate::builtins;
+use crate::runtime;
+use crate::symbol_table::{self, reference_arity, SymbolTable};
+
+// Where `continue` and `break` jump to for one of the enclosing loops
+struct LoopTargets {
+    label: Option<String>,
+    next: String,
+    end: String,
+    // `for` bounds on the stack inside the loop body
+    stack_depth: usize,
+    // Memory slot of the loop variable while it lives in edi
+    spill: Option<String>,
+}
 
 pub struct Assembler<'a> {
     tree: AST,
@@ -14,20 +28,31 @@ pub struct Assembler<'a> {
     functions: Vec<String>,
     start: Vec<String>,
     label_counter: usize,
+    routines: HashSet<&'static str>,
+    source_name: String,
+    bounds_check: bool,
+    array_names: HashSet<String>,
+    checked_arithmetic: bool,
+    current_function: Option<String>,
+    frame: HashMap<String, i32>,
+    return_label: String,
+    loop_register: Option<String>,
+    loops: Vec<LoopTargets>,
+    stack_depth: usize,
 }
 
 impl<'a> Assembler<'a> {
-    pub fn new(tree: AST, symbol_table: &'a mut SymbolTable) -> Self {
+    pub fn new(tree: AST, symbol_table: &'a mut SymbolTable, source_name: &str) -> Self {
         let mut ds: Vec<String> = Vec::new();
         let mut bss: Vec<String> = Vec::new();
-        for symb in &symbol_table.table {
-            if symb.vtype == "int" {
-                ds.push(format!("{} dd 0", symb.vname));
+        for symb in symbol_table.table.iter().filter(|symb| symb.scope.is_none()) {
+            if symb.vtype == "int" || symb.vtype == "ptr" || reference_arity(&symb.vtype).is_some() {
+                ds.push(format!("{} dd 0", global_label(&symb.vname)));
             } else if symb.vtype == "arr" {
-                bss.push(format!("{} resd {}", symb.vname, symb.array_size));
+                bss.push(format!("{} resd {}", global_label(&symb.vname), symb.array_size));
             }
         }
-        bss.push("buffer resb 12".to_string());
+        bss.push("buffer resb 33".to_string());
         symbol_table.reset_level();
         Assembler {
             tree,
@@ -37,9 +62,28 @@ impl<'a> Assembler<'a> {
             functions: Vec::new(),
             start: Vec::new(),
             label_counter: 0,
+            routines: HashSet::new(),
+            source_name: source_name.to_string(),
+            bounds_check: false,
+            array_names: HashSet::new(),
+            checked_arithmetic: false,
+            current_function: None,
+            frame: HashMap::new(),
+            return_label: String::new(),
+            loop_register: None,
+            loops: Vec::new(),
+            stack_depth: 0,
         }
     }
 
+    pub fn set_bounds_check(&mut self, enabled: bool) {
+        self.bounds_check = enabled;
+    }
+
+    pub fn set_checked_arithmetic(&mut self, enabled: bool) {
+        self.checked_arithmetic = enabled;
+    }
+
     pub fn write_to_file(&self, file_path: &str) -> io::Result<()> {
         let path = Path::new(file_path);
         let mut file = File::create(&path)?;
@@ -48,7 +92,7 @@ impl<'a> Assembler<'a> {
         for var in &self.data_section {
             writeln!(file, "{}", var)?;
         }
-        writeln!(file, "newline db 0xA")?;
+        writeln!(file, "start_esp dd 0")?;
 
         writeln!(file, "\nsection .bss")?;
         for var in &self.bss_section {
@@ -59,6 +103,8 @@ impl<'a> Assembler<'a> {
         writeln!(file, "global _start")?;
 
         writeln!(file, "\n_start:")?;
+        // argc and argv sit on the initial stack, keep it for the runtime
+        writeln!(file, "mov [start_esp], esp")?;
         for line in &self.start {
             writeln!(file, "{}", line)?;
         }
@@ -75,8 +121,8 @@ impl<'a> Assembler<'a> {
     }
 
     pub fn generate(&mut self) {
-        for node in self.tree.get_nodes() {
-            self.generate_node(&node);
+        for statement in self.tree.get_nodes() {
+            self.generate_node(&statement.node);
         }
 
         let _ = self.write_to_file("assm/out.asm");
@@ -88,49 +134,340 @@ impl<'a> Assembler<'a> {
         label
     }
 
+    // Emits a runtime routine the first time it is needed.
+    fn require(&mut self, routine: &'static str) {
+        if !self.routines.insert(routine) {
+            return;
+        }
+
+        let body: String = match routine {
+            "print_num" => {
+                self.data_section
+                    .push("digits db \"0123456789abcdef\"".to_string());
+                runtime::print_num()
+            }
+            "print_char" => runtime::print_char(),
+            "assert_fail" => {
+                self.require("print_num");
+                self.require("print_char");
+                self.data_section.push(format!(
+                    "assert_msg db \"assertion failed at {}:\"",
+                    self.source_name
+                ));
+                self.data_section
+                    .push("assert_msg_len equ $ - assert_msg".to_string());
+                runtime::assert_fail()
+            }
+            "bounds_fail" => {
+                self.require("print_num");
+                self.require("print_char");
+                self.data_section
+                    .push("bounds_msg_index db \"index \"".to_string());
+                self.data_section
+                    .push("bounds_msg_index_len equ $ - bounds_msg_index".to_string());
+                self.data_section
+                    .push("bounds_msg_array db \" out of bounds for array \"".to_string());
+                self.data_section
+                    .push("bounds_msg_array_len equ $ - bounds_msg_array".to_string());
+                self.data_section.push(format!(
+                    "bounds_msg_at db \" at {}:\"",
+                    self.source_name
+                ));
+                self.data_section
+                    .push("bounds_msg_at_len equ $ - bounds_msg_at".to_string());
+                runtime::bounds_fail()
+            }
+            "arith_fail" => {
+                self.require("print_num");
+                self.require("print_char");
+                for (label, message) in [
+                    ("arith_msg_add", "overflow in addition"),
+                    ("arith_msg_sub", "overflow in subtraction"),
+                    ("arith_msg_mul", "overflow in multiplication"),
+                    ("arith_msg_neg", "overflow in negation"),
+                    ("arith_msg_pow", "overflow in power"),
+                    ("arith_msg_div", "division by zero"),
+                    ("arith_msg_mod", "modulo by zero"),
+                    ("arith_msg_div_overflow", "overflow in division"),
+                    ("arith_msg_mod_overflow", "overflow in modulo"),
+                ] {
+                    self.data_section
+                        .push(format!("{} db \"{}\"", label, message));
+                    self.data_section
+                        .push(format!("{}_len equ $ - {}", label, label));
+                }
+                self.data_section.push(format!(
+                    "arith_msg_at db \" at {}:\"",
+                    self.source_name
+                ));
+                self.data_section
+                    .push("arith_msg_at_len equ $ - arith_msg_at".to_string());
+                runtime::arith_fail()
+            }
+            "alloc" | "free" => {
+                if self.routines.insert("heap") {
+                    self.data_section.push("heap_free dd 0".to_string());
+                    self.data_section.push("heap_end dd 0".to_string());
+                }
+                if routine == "alloc" {
+                    runtime::alloc()
+                } else {
+                    runtime::free()
+                }
+            }
+            "argc" => runtime::argc(),
+            "arg_int" => runtime::arg_int(),
+            "open" => runtime::open(),
+            "read_bytes" => runtime::read_bytes(),
+            "write_bytes" => runtime::write_bytes(),
+            "close" => runtime::close(),
+            _ => unreachable!("unknown runtime routine {}", routine),
+        };
+        self.functions.push(body);
+    }
+
+    // Expects the index in eax. Aborts through `bounds_fail` when it is
+    // outside of the declared size of the array.
+    fn check_bounds(&mut self, arr_name: &str, line: usize) {
+        if !self.bounds_check {
+            return;
+        }
+        let size: u32 = match self
+            .symbol_table
+            .array_size(arr_name, self.current_function.as_deref())
+        {
+            Some(size) => size,
+            None => return,
+        };
+
+        self.require("bounds_fail");
+        if self.array_names.insert(arr_name.to_string()) {
+            self.data_section
+                .push(format!("arrname_{} db \"{}\"", arr_name, arr_name));
+            self.data_section
+                .push(format!("arrname_{}_len equ $ - arrname_{}", arr_name, arr_name));
+        }
+
+        let ok_label = self.new_label("bounds_ok");
+        self.start.push(format!("cmp eax, {}", size));
+        self.start.push(format!("jb {}", ok_label));
+        self.start.push(format!("mov ebx, {}", line));
+        self.start.push(format!("mov ecx, arrname_{}", arr_name));
+        self.start.push(format!("mov edx, arrname_{}_len", arr_name));
+        self.start.push("call bounds_fail".into());
+        self.start.push(format!("{}:", ok_label));
+    }
+
+    // Expects the flags of the last add/sub/imul. Aborts through `arith_fail`
+    // when the signed result overflowed.
+    fn check_overflow(&mut self, message: &str, line: usize) {
+        if self.checked_arithmetic {
+            self.arith_trap("jno", message, line);
+        }
+    }
+
+    // Expects the divisor in ebx. Aborts through `arith_fail` when it is zero.
+    fn check_divisor(&mut self, message: &str, line: usize) {
+        if self.checked_arithmetic {
+            self.start.push("test ebx, ebx".into());
+            self.arith_trap("jnz", message, line);
+        }
+    }
+
+    // Expects the dividend in eax and the divisor in ebx. Aborts through
+    // `arith_fail` on the one signed division that overflows, -2^31 by -1,
+    // which idiv would otherwise turn into a SIGFPE.
+    fn check_division_overflow(&mut self, message: &str, line: usize) {
+        if self.checked_arithmetic {
+            let ok_label = self.new_label("arith_ok");
+            self.start.push("cmp ebx, -1".into());
+            self.start.push(format!("jne {}", ok_label));
+            self.start.push("cmp eax, 0x80000000".into());
+            self.arith_trap("jne", message, line);
+            self.start.push(format!("{}:", ok_label));
+        }
+    }
+
+    fn arith_trap(&mut self, ok_jump: &str, message: &str, line: usize) {
+        self.require("arith_fail");
+        let ok_label = self.new_label("arith_ok");
+        self.start.push(format!("{} {}", ok_jump, ok_label));
+        self.start.push(format!("mov eax, {}", line));
+        self.start.push(format!("mov ecx, {}", message));
+        self.start.push(format!("mov edx, {}_len", message));
+        self.start.push("call arith_fail".into());
+        self.start.push(format!("{}:", ok_label));
+    }
+
+    // Points esi at the first element of an array or of the memory a
+    // pointer variable refers to.
+    fn load_base(&mut self, arr_name: &str) {
+        let location: String = self.location(arr_name);
+        if self
+            .symbol_table
+            .is_pointer(arr_name, self.current_function.as_deref())
+        {
+            self.start.push(format!("mov esi, [{}]", location));
+        } else {
+            self.start.push(format!("lea esi, [{}]", location));
+        }
+    }
+
+    // Address of a variable, relative to ebp for parameters and locals of
+    // the function being generated.
+    fn location(&self, name: &str) -> String {
+        match self.frame.get(name) {
+            Some(offset) if *offset < 0 => format!("ebp - {}", -offset),
+            Some(offset) => format!("ebp + {}", offset),
+            None => global_label(name),
+        }
+    }
+
+    // Operand holding a variable, edi for the variable of the `for` loop being
+    // generated when it lives in a register.
+    fn operand(&self, name: &str) -> String {
+        if self.loop_register.as_deref() == Some(name) {
+            return "edi".to_string();
+        }
+        format!("[{}]", self.location(name))
+    }
+
+    // Parameters sit above the return address, pushed right to left by the
+    // caller. Locals get slots below the saved ebp. Returns the frame size.
+    fn layout_frame(&mut self, name: &str) -> u32 {
+        self.frame.clear();
+        if let Some(func) = self.symbol_table.function(name) {
+            for (i, (param, _)) in func.params.iter().enumerate() {
+                self.frame.insert(param.clone(), 8 + 4 * i as i32);
+            }
+        }
+
+        let mut size: u32 = 0;
+        for symb in &self.symbol_table.table {
+            if symb.scope.as_deref() != Some(name) || self.frame.contains_key(&symb.vname) {
+                continue;
+            }
+            size += if symb.vtype == "arr" { 4 * symb.array_size } else { 4 };
+            self.frame.insert(symb.vname.clone(), -(size as i32));
+        }
+        size
+    }
+
     fn generate_node(&mut self, node: &ASTNode) {
         match node {
             ASTNode::Assignment { var_name, expr } => {
-                self.generate_node(&expr);
-                self.start.push(format!("mov [{}], eax", var_name));
+                self.generate_node(expr);
+                let operand: String = self.operand(var_name);
+                self.start.push(format!("mov {}, eax", operand));
             }
             ASTNode::ArrayDeclaration { arr_name, size } => {
                 // self.start.push(format!("lea esi, [{}]", arr_name));
             },
-            ASTNode::ArrayAssignment { arr_name, position, value } => {
-                self.start.push(format!("lea esi, [{}]", arr_name));
-                self.generate_node(&position);
-                self.start.push(format!("mov ecx, eax"));
-                self.generate_node(&value);
-                self.start.push(format!("mov [esi + ecx*4], eax",));
-            }
-            ASTNode::BinaryOperation { op, left, right } => {
+            ASTNode::ArrayAssignment { arr_name, position, value, span } => {
+                // The value goes first, calls in it are free to use esi and ecx
+                self.generate_node(value);
+                self.start.push("push eax".into());
+                self.generate_node(position);
+                self.check_bounds(arr_name, span.line);
+                self.start.push("mov ecx, eax".into());
+                self.load_base(arr_name);
+                self.start.push("pop eax".into());
+                self.start.push("mov [esi + ecx*4], eax".into());
+            }
+            ASTNode::BinaryOperation { op, left, right, span } => {
                 self.generate_node(right);
                 self.start.push("push eax".into());
                 self.generate_node(left);
                 self.start.push("pop ebx".into());
 
                 match op.as_str() {
-                    "+" => self.start.push("add eax, ebx".into()),
-                    "-" => self.start.push("sub eax, ebx".into()),
-                    "*" => self.start.push("imul eax, ebx".into()),
+                    "+" => {
+                        self.start.push("add eax, ebx".into());
+                        self.check_overflow("arith_msg_add", span.line);
+                    }
+                    "-" => {
+                        self.start.push("sub eax, ebx".into());
+                        self.check_overflow("arith_msg_sub", span.line);
+                    }
+                    "*" => {
+                        self.start.push("imul eax, ebx".into());
+                        self.check_overflow("arith_msg_mul", span.line);
+                    }
                     "/" => {
-                        self.start.push("mov edx, 0".into());
-                        self.start.push("div ebx".into());
+                        self.check_divisor("arith_msg_div", span.line);
+                        self.check_division_overflow("arith_msg_div_overflow", span.line);
+                        self.start.push("cdq".into());
+                        self.start.push("idiv ebx".into());
                     }
                     "%" => {
-                        self.start.push("mov edx, 0".into());
-                        self.start.push("div ebx".into());
+                        self.check_divisor("arith_msg_mod", span.line);
+                        self.check_division_overflow("arith_msg_mod_overflow", span.line);
+                        self.start.push("cdq".into());
+                        self.start.push("idiv ebx".into());
                         self.start.push("mov eax, edx".into());
                     }
-                    _ => self.start.push("; Unknown binary operator".into()),
+                    // eax to the power ebx by repeated multiplication, a
+                    // negative exponent gives 1 like a zero one
+                    "^" => {
+                        let loop_label: String = self.new_label("pow_loop");
+                        let end_label: String = self.new_label("pow_end");
+                        self.start.push("mov ecx, eax".into());
+                        self.start.push("mov eax, 1".into());
+                        self.start.push(format!("{}:", loop_label));
+                        self.start.push("test ebx, ebx".into());
+                        self.start.push(format!("jle {}", end_label));
+                        self.start.push("imul eax, ecx".into());
+                        self.check_overflow("arith_msg_pow", span.line);
+                        self.start.push("dec ebx".into());
+                        self.start.push(format!("jmp {}", loop_label));
+                        self.start.push(format!("{}:", end_label));
+                    }
+                    // Comparisons used as values give 1 or 0
+                    "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                        let set: &str = match op.as_str() {
+                            "==" => "sete",
+                            "!=" => "setne",
+                            "<" => "setl",
+                            ">" => "setg",
+                            "<=" => "setle",
+                            _ => "setge",
+                        };
+                        self.start.push("cmp eax, ebx".into());
+                        self.start.push(format!("{} al", set));
+                        self.start.push("movzx eax, al".into());
+                    }
+                    _ => unreachable!("unknown binary operator {}", op),
                 }
             }
-            ASTNode::Literal(value) => {
+            ASTNode::UnaryOperation { op, operand, span } => {
+                self.generate_node(operand);
+                match op.as_str() {
+                    "-" => {
+                        self.start.push("neg eax".into());
+                        self.check_overflow("arith_msg_neg", span.line);
+                    }
+                    _ => unreachable!("unknown unary operator {}", op),
+                }
+            }
+            ASTNode::Literal { value, .. } => {
                 self.start.push(format!("mov eax, {}", value));
             }
-            ASTNode::Variable(name) => {
-                self.start.push(format!("mov eax, [{}]", name));
+            ASTNode::StringLiteral { value, .. } => {
+                // Stored NUL terminated, as the syscalls taking paths expect
+                let label = self.new_label("str");
+                let mut bytes: Vec<String> = value.bytes().map(|b| b.to_string()).collect();
+                bytes.push("0".to_string());
+                self.data_section
+                    .push(format!("{} db {}", label, bytes.join(", ")));
+                self.start.push(format!("mov eax, {}", label));
+            }
+            ASTNode::Address { name, .. } => {
+                let location: String = self.location(name);
+                self.start.push(format!("lea eax, [{}]", location));
+            }
+            ASTNode::Variable { name, .. } => {
+                let operand: String = self.operand(name);
+                self.start.push(format!("mov eax, {}", operand));
             }
             ASTNode::IfOperation {
                 first_half,
@@ -147,16 +484,16 @@ impl<'a> Assembler<'a> {
                 let endif_label = self.new_label("endif");
                 match comparison_op.as_str() {
                     "==" => self.start.push(format!("jne {}", endif_label)),
-                    "=!" => self.start.push(format!("je {}", endif_label)),
+                    "!=" => self.start.push(format!("je {}", endif_label)),
                     "<" => self.start.push(format!("jge {}", endif_label)),
                     ">" => self.start.push(format!("jle {}", endif_label)),
-                    "=<" => self.start.push(format!("jg {}", endif_label)),
-                    "=>" => self.start.push(format!("jl {}", endif_label)),
+                    "<=" => self.start.push(format!("jg {}", endif_label)),
+                    ">=" => self.start.push(format!("jl {}", endif_label)),
                     _ => self.start.push("; Unknown comparison".into()),
                 }
 
                 for exp in content {
-                    self.generate_node(exp);
+                    self.generate_node(&exp.node);
                 }
                 self.start.push(format!("{}:", endif_label));
             }
@@ -165,6 +502,7 @@ impl<'a> Assembler<'a> {
                 comparison_op,
                 second_half,
                 content,
+                label,
             } => {
                 let start_label = self.new_label("while_start");
                 let end_label = self.new_label("while_end");
@@ -178,71 +516,402 @@ impl<'a> Assembler<'a> {
 
                 match comparison_op.as_str() {
                     "==" => self.start.push(format!("jne {}", end_label)),
-                    "=!" => self.start.push(format!("je {}", end_label)),
+                    "!=" => self.start.push(format!("je {}", end_label)),
                     "<" => self.start.push(format!("jge {}", end_label)),
                     ">" => self.start.push(format!("jle {}", end_label)),
-                    "=<" => self.start.push(format!("jg {}", end_label)),
-                    "=>" => self.start.push(format!("jl {}", end_label)),
+                    "<=" => self.start.push(format!("jg {}", end_label)),
+                    ">=" => self.start.push(format!("jl {}", end_label)),
+                    _ => self.start.push("; Unknown comparison".into()),
+                }
+
+                self.loops.push(LoopTargets {
+                    label: label.clone(),
+                    next: start_label.clone(),
+                    end: end_label.clone(),
+                    stack_depth: self.stack_depth,
+                    spill: None,
+                });
+                for exp in content {
+                    self.generate_node(&exp.node);
+                }
+                self.loops.pop();
+
+                self.start.push(format!("jmp {}", start_label));
+                self.start.push(format!("{}:", end_label));
+            }
+            ASTNode::DoUntil {
+                first_half,
+                comparison_op,
+                second_half,
+                content,
+                label,
+            } => {
+                // The condition is tested at the bottom, jumping back while
+                // it doesn't hold yet
+                let start_label = self.new_label("do_start");
+                let next_label = self.new_label("do_next");
+                let end_label = self.new_label("do_end");
+
+                self.start.push(format!("{}:", start_label));
+                self.loops.push(LoopTargets {
+                    label: label.clone(),
+                    next: next_label.clone(),
+                    end: end_label.clone(),
+                    stack_depth: self.stack_depth,
+                    spill: None,
+                });
+                for exp in content {
+                    self.generate_node(&exp.node);
+                }
+                self.loops.pop();
+
+                self.start.push(format!("{}:", next_label));
+                self.generate_node(second_half);
+                self.start.push("push eax".into());
+                self.generate_node(first_half);
+                self.start.push("pop ebx".into());
+                self.start.push("cmp eax, ebx".into());
+
+                match comparison_op.as_str() {
+                    "==" => self.start.push(format!("jne {}", start_label)),
+                    "!=" => self.start.push(format!("je {}", start_label)),
+                    "<" => self.start.push(format!("jge {}", start_label)),
+                    ">" => self.start.push(format!("jle {}", start_label)),
+                    "<=" => self.start.push(format!("jg {}", start_label)),
+                    ">=" => self.start.push(format!("jl {}", start_label)),
                     _ => self.start.push("; Unknown comparison".into()),
                 }
+                self.start.push(format!("{}:", end_label));
+            }
+            ASTNode::ForOperation {
+                var_name,
+                from,
+                to,
+                step,
+                content,
+                label,
+            } => {
+                let start_label = self.new_label("for_start");
+                let next_label = self.new_label("for_next");
+                let end_label = self.new_label("for_end");
+                let in_register: bool = register_eligible(content);
+                let memory: String = format!("[{}]", self.location(var_name));
+
+                self.generate_node(from);
+                self.start.push(format!("mov {}, eax", memory));
+                // The bound is evaluated once and kept on the stack
+                self.generate_node(to);
+                self.start.push("push eax".into());
+                if in_register {
+                    self.start.push(format!("mov edi, {}", memory));
+                    self.loop_register = Some(var_name.clone());
+                }
+                let counter: String = self.operand(var_name);
 
+                self.start.push(format!("{}:", start_label));
+                self.start.push(format!("mov eax, {}", counter));
+                self.start.push("cmp eax, [esp]".into());
+                if *step > 0 {
+                    self.start.push(format!("jge {}", end_label));
+                } else {
+                    self.start.push(format!("jle {}", end_label));
+                }
+
+                self.stack_depth += 1;
+                self.loops.push(LoopTargets {
+                    label: label.clone(),
+                    next: next_label.clone(),
+                    end: end_label.clone(),
+                    stack_depth: self.stack_depth,
+                    spill: if in_register { Some(memory.clone()) } else { None },
+                });
                 for exp in content {
-                    self.generate_node(exp);
+                    self.generate_node(&exp.node);
                 }
+                self.loops.pop();
+                self.stack_depth -= 1;
 
+                self.start.push(format!("{}:", next_label));
+                if in_register {
+                    self.start.push(format!("add edi, {}", step));
+                } else {
+                    self.start.push(format!("add dword {}, {}", counter, step));
+                }
                 self.start.push(format!("jmp {}", start_label));
                 self.start.push(format!("{}:", end_label));
+                self.start.push("add esp, 4".into());
+                if in_register {
+                    self.start.push(format!("mov {}, edi", memory));
+                    self.loop_register = None;
+                }
+            }
+            ASTNode::Break(label) | ASTNode::Continue(label) => {
+                // The parser only lets through jumps to an enclosing loop
+                let Some(target) = self
+                    .loops
+                    .iter()
+                    .rposition(|targets| label.is_none() || targets.label == *label)
+                else {
+                    self.start.push("; Jump outside of a loop".into());
+                    return;
+                };
+
+                // Loops being left keep their counter in edi or a bound on the stack
+                for inner in &self.loops[target + 1..] {
+                    if let Some(memory) = &inner.spill {
+                        self.start.push(format!("mov {}, edi", memory));
+                    }
+                }
+                let extra: usize = self.stack_depth - self.loops[target].stack_depth;
+                if extra > 0 {
+                    self.start.push(format!("add esp, {}", 4 * extra));
+                }
+
+                let destination: &str = match node {
+                    ASTNode::Break(_) => &self.loops[target].end,
+                    _ => &self.loops[target].next,
+                };
+                self.start.push(format!("jmp {}", destination));
             }
-            ASTNode::EndIf | ASTNode::EndWhile | ASTNode::Start | ASTNode::End => {}
             ASTNode::Error => {
                 self.start.push("; Error node encountered".into());
             }
-            ASTNode::FunctionDef { name, parameters } => todo!(),
-            ASTNode::EndFunctionDef => todo!(),
-            ASTNode::FuntionCall { name, parameters } => todo!(),
-            ASTNode::Print(astnode) => {
-                self.generate_node(astnode);
-                self.start.push("call print_eax".into());
-
-                let mut print_func: String = String::new();
-                print_func.push_str("print_eax:\n");
-                print_func.push_str("push ecx\n");
-                print_func.push_str("push edx\n");
-                print_func.push_str("mov edi, buffer + 11\n");
-                print_func.push_str("mov byte [edi], 0\n");
-                print_func.push_str("mov ebx, 10\n");
-                print_func.push_str(".convert_loop:\n");
-                print_func.push_str("dec edi\n");
-                print_func.push_str("xor edx, edx\n");
-                print_func.push_str("div ebx\n");
-                print_func.push_str("add dl, '0'\n");
-                print_func.push_str("mov [edi], dl\n");
-                print_func.push_str("test eax, eax\n");
-                print_func.push_str("jnz .convert_loop\n");
-                print_func.push_str("mov eax, 4\n");
-                print_func.push_str("mov ebx, 1\n");
-                print_func.push_str("mov ecx, edi\n");
-                print_func.push_str("mov edx, buffer + 11\n");
-                print_func.push_str("sub edx, edi\n");
-                print_func.push_str("int 0x80\n");
-                print_func.push_str("mov eax, 4\n");
-                print_func.push_str("mov ebx, 1\n");
-                print_func.push_str("mov ecx, newline\n");
-                print_func.push_str("mov edx, 1\n");
-                print_func.push_str("int 0x80\n");
-                print_func.push_str("pop edx\n");
-                print_func.push_str("pop ecx\n");
-                print_func.push_str("ret\n");
-
-                if self.functions.is_empty() {
-                    self.functions.push(print_func);
-                }
-            }
-            ASTNode::Array { arr_name, index } => {
+            ASTNode::FunctionDef { name, content, .. } => {
+                // Functions are generated on the side and appended after _start
+                let main_body: Vec<String> = std::mem::take(&mut self.start);
+                let frame_size: u32 = self.layout_frame(name);
+                self.current_function = Some(name.clone());
+                self.return_label = self.new_label("return");
+
+                self.start.push(format!("func_{}:", name));
+                self.start.push("push ebp".into());
+                self.start.push("mov ebp, esp".into());
+                if frame_size > 0 {
+                    self.start.push(format!("sub esp, {}", frame_size));
+                }
+                for exp in content {
+                    self.generate_node(&exp.node);
+                }
+                self.start.push("xor eax, eax".into());
+                self.start.push(format!("{}:", self.return_label));
+                self.start.push("mov esp, ebp".into());
+                self.start.push("pop ebp".into());
+                self.start.push("ret".into());
+
+                let body: Vec<String> = std::mem::replace(&mut self.start, main_body);
+                self.functions.push(body.join("\n") + "\n");
+                self.current_function = None;
+                self.frame.clear();
+            }
+            ASTNode::InlineAsm(lines) => {
+                for line in lines {
+                    let mut code: String = line.clone();
+                    for name in asm_references(line) {
+                        let operand: String = format!("[{}]", self.location(name));
+                        code = code.replacen(&format!("{{{}}}", name), &operand, 1);
+                    }
+                    self.start.push(code);
+                }
+            }
+            ASTNode::Return(value) => {
+                match value {
+                    Some(value) => self.generate_node(value),
+                    None => self.start.push("xor eax, eax".into()),
+                }
+                self.start.push(format!("jmp {}", self.return_label));
+            }
+            ASTNode::Call { name, args, .. } => {
+                for arg in args.iter().rev() {
+                    self.generate_node(arg);
+                    self.start.push("push eax".into());
+                }
+                self.start.push(format!("call func_{}", name));
+                if !args.is_empty() {
+                    self.start.push(format!("add esp, {}", 4 * args.len()));
+                }
+            }
+            ASTNode::FunctionRef { name, .. } => {
+                self.start.push(format!("mov eax, func_{}", name));
+            }
+            ASTNode::IndirectCall { target, args, .. } => {
+                for arg in args.iter().rev() {
+                    self.generate_node(arg);
+                    self.start.push("push eax".into());
+                }
+                let operand: String = self.operand(target);
+                self.start.push(format!("mov eax, {}", operand));
+                self.start.push("call eax".into());
+                if !args.is_empty() {
+                    self.start.push(format!("add esp, {}", 4 * args.len()));
+                }
+            }
+            ASTNode::Print { args, newline } => {
+                self.require("print_num");
+                for (i, (base, value)) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.require("print_char");
+                        self.start.push("mov eax, ' '".into());
+                        self.start.push("mov ecx, 1".into());
+                        self.start.push("call print_char".into());
+                    }
+                    self.generate_node(value);
+                    self.start.push(format!("mov ebx, {}", base));
+                    self.start.push("mov ecx, 1".into());
+                    self.start.push("call print_num".into());
+                }
+                if *newline {
+                    self.require("print_char");
+                    self.start.push("mov eax, 0xA".into());
+                    self.start.push("mov ecx, 1".into());
+                    self.start.push("call print_char".into());
+                }
+            }
+            ASTNode::BuiltinCall { name, args, .. } => {
+                // Arguments go to the routine in eax, ebx, ecx and edx
+                for arg in args.iter().rev() {
+                    self.generate_node(arg);
+                    self.start.push("push eax".into());
+                }
+                for register in ["eax", "ebx", "ecx", "edx"].iter().take(args.len()) {
+                    self.start.push(format!("pop {}", register));
+                }
+                let routine: &'static str =
+                    builtins::routine(name).expect("Unknown builtin");
+                self.require(routine);
+                self.start.push(format!("call {}", routine));
+            }
+            ASTNode::Exit(code) => {
+                self.generate_node(code);
+                self.start.push("mov ebx, eax".into());
+                self.start.push("mov eax, 1".into());
+                self.start.push("int 0x80".into());
+            }
+            ASTNode::Assert {
+                first_half,
+                comparison_op,
+                second_half,
+                span,
+            } => {
+                self.require("assert_fail");
+                self.generate_node(second_half);
+                self.start.push("push eax".into());
+                self.generate_node(first_half);
+                self.start.push("pop ebx".into());
+                self.start.push("cmp eax, ebx".into());
+
+                let ok_label = self.new_label("assert_ok");
+                match comparison_op.as_str() {
+                    "==" => self.start.push(format!("je {}", ok_label)),
+                    "!=" => self.start.push(format!("jne {}", ok_label)),
+                    "<" => self.start.push(format!("jl {}", ok_label)),
+                    ">" => self.start.push(format!("jg {}", ok_label)),
+                    "<=" => self.start.push(format!("jle {}", ok_label)),
+                    ">=" => self.start.push(format!("jge {}", ok_label)),
+                    _ => self.start.push("; Unknown comparison".into()),
+                }
+
+                self.start.push(format!("mov eax, {}", span.line));
+                self.start.push("call assert_fail".into());
+                self.start.push(format!("{}:", ok_label));
+            }
+            ASTNode::Array { arr_name, index, span } => {
                 self.generate_node(index);
-                self.start.push(format!("lea esi, [{}]", arr_name));
+                self.check_bounds(arr_name, span.line);
+                self.load_base(arr_name);
                 self.start.push(format!("mov eax, [esi + eax*4]"));
             }
         }
     }
 }
+
+// A `for` loop keeps its variable in edi unless its body calls functions, has
+// inline assembly, returns early or holds another `for` loop. Only the
+// innermost loops qualify and edi is never live across a call.
+fn register_eligible(content: &[Spanned]) -> bool {
+    let mut eligible: bool = true;
+    for statement in content {
+        walk(&statement.node, &mut |node| {
+            if matches!(
+                node,
+                ASTNode::Call { .. }
+                    | ASTNode::IndirectCall { .. }
+                    | ASTNode::InlineAsm(_)
+                    | ASTNode::Return(_)
+                    | ASTNode::ForOperation { .. }
+            ) {
+                eligible = false;
+            }
+        });
+    }
+    eligible
+}
+
+// Label of a global variable. User names get a prefix so that a variable
+// called `eax`, `mov` or `buffer` can't clash with registers, instructions
+// or the runtime's own labels, the same way functions get `func_`.
+fn global_label(name: &str) -> String {
+    format!("v_{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::token::Token;
+    use crate::tokenizer::Tokenizer;
+
+    // Code generated for the statements of `source`, without the sections
+    fn compile(source: &str) -> Vec<String> {
+        generate(source, false)
+    }
+
+    fn compile_checked(source: &str) -> Vec<String> {
+        generate(source, true)
+    }
+
+    fn generate(source: &str, checked_arithmetic: bool) -> Vec<String> {
+        let mut tokenizer: Tokenizer = Tokenizer::new(source);
+        let mut tokens: Vec<Token> = Vec::new();
+        while !tokenizer.is_done() {
+            if let Some(token) = tokenizer.get_next_token() {
+                tokens.push(token);
+            }
+        }
+        let mut symbol_table: SymbolTable = SymbolTable::new();
+        let mut parser: Parser = Parser::new(tokens, &mut symbol_table);
+        let tree: AST = AST::new(parser.parse_program());
+        assert!(parser.diagnostics().is_empty(), "{}: {:?}", source, parser.diagnostics());
+
+        let mut assembler: Assembler = Assembler::new(tree, &mut symbol_table, "test.idk");
+        assembler.set_checked_arithmetic(checked_arithmetic);
+        for statement in assembler.tree.get_nodes() {
+            assembler.generate_node(&statement.node);
+        }
+        assembler.start
+    }
+
+    #[test]
+    fn power_multiplies_in_a_loop() {
+        let code: Vec<String> = compile("var x = 2 ^ 3;");
+        assert!(code.contains(&"imul eax, ecx".to_string()), "{:?}", code);
+        assert!(code.iter().all(|line| !line.starts_with(';')), "{:?}", code);
+    }
+    #[test]
+    fn division_is_signed() {
+        for source in ["var x = -7 / 2;", "var x = -7 % 2;"] {
+            let code: Vec<String> = compile(source);
+            let divide: usize = code.iter().position(|line| line == "idiv ebx").expect(source);
+            assert_eq!(code[divide - 1], "cdq", "{}", source);
+            assert!(!code.contains(&"div ebx".to_string()), "{}", source);
+        }
+    }
+    #[test]
+    fn checked_division_traps_on_overflow() {
+        let code: Vec<String> = compile_checked("var x = -2147483647 - 1;\nvar y = x / -1;");
+        let divide: usize = code.iter().position(|line| line == "idiv ebx").unwrap();
+        let guard: &[String] = &code[..divide];
+        assert!(guard.contains(&"cmp ebx, -1".to_string()), "{:?}", code);
+        assert!(guard.contains(&"cmp eax, 0x80000000".to_string()), "{:?}", code);
+        assert!(guard.contains(&"mov ecx, arith_msg_div_overflow".to_string()), "{:?}", code);
+    }
+}
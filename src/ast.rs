@@ -1,5 +1,8 @@
+use std::collections::HashSet;
 use std::usize;
 
+use crate::token::Span;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum ASTNode {
     Assignment {
@@ -14,167 +17,279 @@ pub enum ASTNode {
         arr_name: String,
         position: Box<ASTNode>,
         value: Box<ASTNode>,
+        span: Span,
     },
     BinaryOperation {
         op: String,
         left: Box<ASTNode>,
         right: Box<ASTNode>,
+        span: Span,
+    },
+    UnaryOperation {
+        op: String,
+        operand: Box<ASTNode>,
+        span: Span,
     },
     IfOperation {
         first_half: Box<ASTNode>,
         comparison_op: String,
         second_half: Box<ASTNode>,
-        content: Vec<Box<ASTNode>>,
+        content: Vec<Spanned>,
     },
-    EndIf,
     WhileOperation {
         first_half: Box<ASTNode>,
         comparison_op: String,
         second_half: Box<ASTNode>,
-        content: Vec<Box<ASTNode>>,
+        content: Vec<Spanned>,
+        label: Option<String>,
+    },
+    ForOperation {
+        var_name: String,
+        from: Box<ASTNode>,
+        to: Box<ASTNode>,
+        step: i64,
+        content: Vec<Spanned>,
+        label: Option<String>,
+    },
+    DoUntil {
+        first_half: Box<ASTNode>,
+        comparison_op: String,
+        second_half: Box<ASTNode>,
+        content: Vec<Spanned>,
+        label: Option<String>,
     },
-    EndWhile,
     FunctionDef {
         name: String,
         parameters: Option<Vec<String>>,
-    },
-    EndFunctionDef,
-    FuntionCall {
-        name: String,
-        parameters: Option<Vec<(String, String)>>,
+        content: Vec<Spanned>,
     },
     Array {
         arr_name: String,
         index: Box<ASTNode>,
+        span: Span,
+    },
+    Print {
+        args: Vec<(u32, Box<ASTNode>)>,
+        newline: bool,
+    },
+    BuiltinCall {
+        name: String,
+        args: Vec<ASTNode>,
+        span: Span,
+    },
+    Call {
+        name: String,
+        args: Vec<ASTNode>,
+        span: Span,
+    },
+    Return(Option<Box<ASTNode>>),
+    // Address of a function, used as a value
+    FunctionRef {
+        name: String,
+        span: Span,
+    },
+    // Call through a variable holding a function reference
+    IndirectCall {
+        target: String,
+        args: Vec<ASTNode>,
+        span: Span,
+    },
+    InlineAsm(Vec<String>),
+    Break(Option<String>),
+    Continue(Option<String>),
+    Exit(Box<ASTNode>),
+    Assert {
+        first_half: Box<ASTNode>,
+        comparison_op: String,
+        second_half: Box<ASTNode>,
+        span: Span,
+    },
+    Literal {
+        value: String,
+        span: Span,
+    },
+    StringLiteral {
+        value: String,
+        span: Span,
+    },
+    Address {
+        name: String,
+        span: Span,
+    },
+    Variable {
+        name: String,
+        span: Span,
     },
-    Print(Box<ASTNode>),
-    Literal(String),
-    Variable(String),
-    Start,
-    End,
     Error,
 }
 
+impl ASTNode {
+    // Where an expression comes from, statements carry theirs in `Spanned`
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ASTNode::ArrayAssignment { span, .. }
+            | ASTNode::BinaryOperation { span, .. }
+            | ASTNode::UnaryOperation { span, .. }
+            | ASTNode::Array { span, .. }
+            | ASTNode::BuiltinCall { span, .. }
+            | ASTNode::Call { span, .. }
+            | ASTNode::FunctionRef { span, .. }
+            | ASTNode::IndirectCall { span, .. }
+            | ASTNode::Assert { span, .. }
+            | ASTNode::Literal { span, .. }
+            | ASTNode::StringLiteral { span, .. }
+            | ASTNode::Address { span, .. }
+            | ASTNode::Variable { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+}
+
+// A statement along with the source it was parsed from
+#[derive(Debug, PartialEq, Clone)]
+pub struct Spanned {
+    pub node: ASTNode,
+    pub span: Span,
+}
+
 #[derive(Debug)]
 pub struct AST {
-    pub nodes: Vec<ASTNode>,
+    pub nodes: Vec<Spanned>,
 }
 
 impl AST {
-    pub fn new(nodes: Vec<ASTNode>) -> Self {
+    pub fn new(nodes: Vec<Spanned>) -> Self {
         AST { nodes }
     }
 
-    pub fn add_node(&mut self, node: ASTNode) {
-        self.nodes.push(node);
-    }
-
-    pub fn get_node(&self, index: usize) -> Option<&ASTNode> {
-        self.nodes.get(index)
-    }
-
-    pub fn get_nodes(&self) -> Vec<ASTNode> {
+    pub fn get_nodes(&self) -> Vec<Spanned> {
         self.nodes.clone()
     }
 
-    pub fn transform_ast(&mut self){
-        let mut new_nodes = Vec::new();
-        let mut iter = self.nodes.clone().into_iter();
-
-        while let Some(node) = iter.next() {
-            match node {
-                ASTNode::WhileOperation { first_half, comparison_op, second_half, .. } => {
-                    let mut content = Vec::new();
-
-                    while let Some(next_node) = iter.next() {
-                        match next_node {
-                            ASTNode::EndWhile => break,
-                            _ => content.push(Box::new(next_node)),
-                        }
-                    }
-
-                    new_nodes.push(ASTNode::WhileOperation { first_half, comparison_op, second_half, content });
-                }
-                ASTNode::IfOperation { first_half, comparison_op, second_half, .. } => {
-                    let mut content = Vec::new();
-
-                    while let Some(next_node) = iter.next() {
-                        match next_node {
-                            ASTNode::EndIf => break,
-                            _ => content.push(Box::new(next_node)),
-                        }
-                    }
+    // Appends the function definitions of `library` that the program calls,
+    // directly or through other library functions. Functions the program
+    // defines itself are left out.
+    pub fn link(&mut self, library: Vec<Spanned>) {
+        let mut defined: HashSet<String> = HashSet::new();
+        let mut called: HashSet<String> = HashSet::new();
+        for statement in &self.nodes {
+            if let ASTNode::FunctionDef { name, .. } = &statement.node {
+                defined.insert(name.clone());
+            }
+            collect_calls(&statement.node, &mut called);
+        }
 
-                    new_nodes.push(ASTNode::IfOperation { first_half, comparison_op, second_half, content });
-                }
-                other => new_nodes.push(other),
+        let mut pending: Vec<String> = called.into_iter().collect();
+        while let Some(name) = pending.pop() {
+            if defined.contains(&name) {
+                continue;
+            }
+            let func = library.iter().find(|statement| {
+                matches!(&statement.node, ASTNode::FunctionDef { name: func_name, .. } if *func_name == name)
+            });
+            if let Some(func) = func {
+                defined.insert(name);
+                let mut nested: HashSet<String> = HashSet::new();
+                collect_calls(&func.node, &mut nested);
+                pending.extend(nested);
+                self.nodes.push(func.clone());
             }
         }
-        self.nodes = new_nodes;
     }
+}
 
-     pub fn bttr_transform_ast(&mut self) {
-        fn collect_block<I>(iter: &mut I, end_marker: ASTNode) -> Vec<Box<ASTNode>>
-        where
-            I: Iterator<Item = ASTNode>,
-        {
-            let mut content = Vec::new();
+// Calls `visit` on `node` and on every node nested inside it.
+pub fn walk(node: &ASTNode, visit: &mut dyn FnMut(&ASTNode)) {
+    visit(node);
+    match node {
+        ASTNode::Call { args, .. }
+        | ASTNode::BuiltinCall { args, .. }
+        | ASTNode::IndirectCall { args, .. } => {
+            for arg in args {
+                walk(arg, visit);
+            }
+        }
+        ASTNode::Assignment { expr, .. } => walk(expr, visit),
+        ASTNode::ArrayAssignment { position, value, .. } => {
+            walk(position, visit);
+            walk(value, visit);
+        }
+        ASTNode::BinaryOperation { left, right, .. } => {
+            walk(left, visit);
+            walk(right, visit);
+        }
+        ASTNode::IfOperation { first_half, second_half, content, .. }
+        | ASTNode::WhileOperation { first_half, second_half, content, .. }
+        | ASTNode::DoUntil { first_half, second_half, content, .. } => {
+            walk(first_half, visit);
+            walk(second_half, visit);
+            for statement in content {
+                walk(&statement.node, visit);
+            }
+        }
+        ASTNode::ForOperation { from, to, content, .. } => {
+            walk(from, visit);
+            walk(to, visit);
+            for statement in content {
+                walk(&statement.node, visit);
+            }
+        }
+        ASTNode::Assert { first_half, second_half, .. } => {
+            walk(first_half, visit);
+            walk(second_half, visit);
+        }
+        ASTNode::FunctionDef { content, .. } => {
+            for statement in content {
+                walk(&statement.node, visit);
+            }
+        }
+        ASTNode::UnaryOperation { operand, .. } => walk(operand, visit),
+        ASTNode::Array { index, .. } => walk(index, visit),
+        ASTNode::Print { args, .. } => {
+            for (_, value) in args {
+                walk(value, visit);
+            }
+        }
+        ASTNode::Exit(value) | ASTNode::Return(Some(value)) => walk(value, visit),
+        _ => {}
+    }
+}
 
-            while let Some(node) = iter.next() {
-                match node {
-                    ASTNode::IfOperation { first_half, comparison_op, second_half, .. } => {
-                        let nested_content = collect_block(iter, ASTNode::EndIf);
-                        content.push(Box::new(ASTNode::IfOperation {
-                            first_half,
-                            comparison_op,
-                            second_half,
-                            content: nested_content,
-                        }));
-                    }
-                    ASTNode::WhileOperation { first_half, comparison_op, second_half, .. } => {
-                        let nested_content = collect_block(iter, ASTNode::EndWhile);
-                        content.push(Box::new(ASTNode::WhileOperation {
-                            first_half,
-                            comparison_op,
-                            second_half,
-                            content: nested_content,
-                        }));
+// Names of the functions called or referenced anywhere inside `node`.
+pub fn collect_calls(node: &ASTNode, calls: &mut HashSet<String>) {
+    walk(node, &mut |node| match node {
+        ASTNode::Call { name, .. } | ASTNode::FunctionRef { name, .. } => {
+            calls.insert(name.clone());
+        }
+        // Functions called by hand from inline assembly
+        ASTNode::InlineAsm(lines) => {
+            for line in lines {
+                let words = line.split(|ch: char| !(ch.is_alphanumeric() || ch == '_'));
+                for word in words {
+                    if let Some(name) = word.strip_prefix("func_") {
+                        calls.insert(name.to_string());
                     }
-                    node if node == end_marker => break,
-                    other => content.push(Box::new(other)),
                 }
             }
-
-            content
         }
+        _ => {}
+    });
+}
 
-        let mut new_nodes = Vec::new();
-        let mut iter = self.nodes.clone().into_iter();
-
-        while let Some(node) = iter.next() {
-            match node {
-                ASTNode::IfOperation { first_half, comparison_op, second_half, .. } => {
-                    let content = collect_block(&mut iter, ASTNode::EndIf);
-                    new_nodes.push(ASTNode::IfOperation {
-                        first_half,
-                        comparison_op,
-                        second_half,
-                        content,
-                    });
-                }
-                ASTNode::WhileOperation { first_half, comparison_op, second_half, .. } => {
-                    let content = collect_block(&mut iter, ASTNode::EndWhile);
-                    new_nodes.push(ASTNode::WhileOperation {
-                        first_half,
-                        comparison_op,
-                        second_half,
-                        content,
-                    });
-                }
-                other => new_nodes.push(other),
+// Variable names referenced as `{name}` inside an asm block.
+pub fn asm_references(body: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    let mut rest: &str = body;
+    while let Some(open) = rest.find('{') {
+        rest = &rest[open + 1..];
+        match rest.find('}') {
+            Some(close) => {
+                names.push(&rest[..close]);
+                rest = &rest[close + 1..];
             }
+            None => break,
         }
-
-        self.nodes = new_nodes;
     }
+
+    names
 }
@@ -0,0 +1,27 @@
+// Functions provided by the runtime that programs can call as `name(args)`.
+// Each one is implemented by the routine of the same name in `runtime`.
+const BUILTINS: [(&str, usize); 8] = [
+    ("alloc", 1),
+    ("free", 1),
+    ("argc", 0),
+    ("arg_int", 1),
+    ("open", 2),
+    ("read_bytes", 3),
+    ("write_bytes", 3),
+    ("close", 1),
+];
+
+pub fn arity(name: &str) -> Option<usize> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, arity)| *arity)
+}
+
+// Name of the runtime routine implementing a builtin.
+pub fn routine(name: &str) -> Option<&'static str> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(builtin, _)| *builtin)
+}
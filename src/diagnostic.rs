@@ -0,0 +1,159 @@
+use crate::token::Span;
+
+// Problems found while compiling, reported all at once by `main` with the
+// offending source lines underlined.
+
+// Error codes are stable, a code keeps its meaning even if the message
+// wording changes.
+pub const PREPROCESSOR: &str = "E0001";
+pub const UNEXPECTED_TOKEN: &str = "E0100";
+pub const MALFORMED_STATEMENT: &str = "E0101";
+pub const BAD_FOR_STEP: &str = "E0102";
+pub const INVALID_LITERAL: &str = "E0103";
+pub const UNTERMINATED: &str = "E0104";
+pub const UNKNOWN_ESCAPE: &str = "E0105";
+pub const UNDEFINED_VARIABLE: &str = "E0200";
+pub const UNDEFINED_FUNCTION: &str = "E0201";
+pub const BUILTIN_NAME: &str = "E0202";
+pub const TYPE_MISMATCH: &str = "E0300";
+pub const ARGUMENT_MISMATCH: &str = "E0301";
+pub const MISPLACED_STATEMENT: &str = "E0400";
+pub const LOOP_LABEL: &str = "E0401";
+pub const UNCLOSED_BLOCK: &str = "E0402";
+pub const NO_EFFECT: &str = "W0100";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message: message.into(),
+            span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(code, message, span)
+        }
+    }
+
+    // Points at another place in the source that explains the problem
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    // Renders the diagnostic like
+    //
+    //   error[E0201]: Call to undefined function foo
+    //    --> main.idk:3:9
+    //     |
+    //   3 | var x = foo(1);
+    //     |         ^^^
+    //     = note: ...
+    //
+    // the primary span is underlined with `^` and labels with `-`.
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let severity: &str = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let mut marks: Vec<(Span, char, &str)> = vec![(self.span, '^', "")];
+        marks.extend(self.labels.iter().map(|label| (label.span, '-', label.message.as_str())));
+        marks.sort_by_key(|(span, _, _)| (span.line, span.column));
+
+        let width: usize = marks
+            .iter()
+            .map(|(span, _, _)| span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter: String = " ".repeat(width);
+        let lines: Vec<&str> = source.lines().collect();
+
+        let mut out: String = format!("{}[{}]: {}\n", severity, self.code, self.message);
+        out += &format!("{}--> {}:{}:{}\n", gutter, path, self.span.line, self.span.column);
+        out += &format!("{} |\n", gutter);
+
+        let mut last_line: usize = 0;
+        for (span, marker, message) in marks {
+            let Some(text) = span.line.checked_sub(1).and_then(|index| lines.get(index)) else {
+                continue;
+            };
+            if span.line != last_line {
+                out += &format!("{:>width$} | {}\n", span.line, text);
+                last_line = span.line;
+            }
+
+            // Spans running over several lines stop at the end of the first
+            let column: usize = span.column.max(1);
+            let room: usize = text.chars().count().saturating_sub(column - 1);
+            let length: usize = source
+                .get(span.start..span.end)
+                .map_or(1, |spanned| spanned.chars().count())
+                .clamp(1, room.max(1));
+            out += &format!(
+                "{} | {}{}",
+                gutter,
+                " ".repeat(column - 1),
+                marker.to_string().repeat(length)
+            );
+            if !message.is_empty() {
+                out += &format!(" {}", message);
+            }
+            out += "\n";
+        }
+
+        for note in &self.notes {
+            out += &format!("{} = note: {}\n", gutter, note);
+        }
+
+        out
+    }
+}
+
+// Prints the diagnostics to stderr, returns whether any of them is an error.
+pub fn emit(diagnostics: &[Diagnostic], path: &str, source: &str) -> bool {
+    for diagnostic in diagnostics {
+        eprint!("{}", diagnostic.render(path, source));
+        eprintln!();
+    }
+    diagnostics.iter().any(Diagnostic::is_error)
+}
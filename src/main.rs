@@ -1,28 +1,74 @@
 mod assembler;
 mod ast;
-mod fsm;
+mod builtins;
+mod diagnostic;
 mod parser;
+mod prelude;
+mod preprocessor;
+mod runtime;
 mod symbol_table;
 mod token;
 mod tokenizer;
 
 use assembler::Assembler;
-use ast::{ASTNode, AST};
+use ast::{Spanned, AST};
+use diagnostic::Diagnostic;
 use parser::Parser;
+use preprocessor::Preprocessor;
 use symbol_table::SymbolTable;
-use token::Token;
+use token::{Span, Token};
 use tokenizer::Tokenizer;
 
-fn main() -> std::io::Result<()> {
-    if std::env::args().len() != 2 {
-        println!("Incorrect usage");
-        println!("Correct usage: idk source.idk");
-        std::process::exit(101);
+fn usage() -> ! {
+    eprintln!("Incorrect usage");
+    eprintln!(
+        "Correct usage: idk [--bounds-check] [--checked-arithmetic] [--legacy-conditions] [--dump] [-D NAME=VAL]... source.idk"
+    );
+    std::process::exit(101);
+}
+
+// `NAME=VAL`, or just `NAME` which defines it as 1
+fn parse_define(option: &str) -> (String, String) {
+    let (name, value) = option.split_once('=').unwrap_or((option, "1"));
+    if name.is_empty() {
+        usage();
     }
+    (name.to_string(), value.to_string())
+}
 
+fn main() -> std::io::Result<()> {
     let argv: Vec<String> = std::env::args().collect();
-    let source_file_path: &str = &argv[1];
+    let mut source_file_path: Option<&str> = None;
+    let mut bounds_check: bool = false;
+    let mut checked_arithmetic: bool = false;
+    let mut legacy_conditions: bool = false;
+    let mut dump: bool = false;
+    let mut defines: Vec<(String, String)> = Vec::new();
+
+    let mut args = argv[1..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bounds-check" => bounds_check = true,
+            "--checked-arithmetic" => checked_arithmetic = true,
+            "--legacy-conditions" => legacy_conditions = true,
+            "--dump" => dump = true,
+            "-D" => defines.push(parse_define(args.next().unwrap_or_else(|| usage()))),
+            define if define.starts_with("-D") => defines.push(parse_define(&define[2..])),
+            flag if flag.starts_with("--") => usage(),
+            path if source_file_path.is_none() => source_file_path = Some(path),
+            _ => usage(),
+        }
+    }
+
+    let source_file_path: &str = source_file_path.unwrap_or_else(|| usage());
     let source_code = std::fs::read_to_string(source_file_path)?;
+    let processed: String = match Preprocessor::new(&defines).process(&source_code) {
+        Ok(code) => code,
+        Err(err) => {
+            diagnostic::emit(&[*err], source_file_path, &source_code);
+            std::process::exit(1);
+        }
+    };
     let mut tokenizer: Tokenizer;
     let mut tokens: Vec<Token> = Vec::new();
     let mut parser: Parser;
@@ -30,39 +76,69 @@ fn main() -> std::io::Result<()> {
     let mut assembler: Assembler;
     let mut symbol_table: SymbolTable = SymbolTable::new();
 
-    tokenizer = Tokenizer::new(source_code.as_str());
+    tokenizer = Tokenizer::new(processed.as_str());
     while !tokenizer.is_done() {
         if let Some(token) = tokenizer.get_next_token() {
             tokens.push(token.clone());
         }
     }
-    // println!("{:?}", tokens);
-    Tokenizer::fix_comparison_operators(&mut tokens);
-    println!("{:?}", tokens);
-
-    parser = Parser::new(tokens, &mut symbol_table);
-    ast = AST::new(vec![]);
-    ast.add_node(ASTNode::Start);
+    // Tokens, tree and symbol table are only printed when asked for
+    if dump {
+        println!("{:?}", tokens);
+    }
 
-    let mut node: ASTNode = parser.parse();
+    // The prelude goes first so the program sees its function signatures
+    let library: Vec<Spanned> = {
+        let (library, prelude_diagnostics) = prelude::parse(&tokens, &mut symbol_table);
+        if diagnostic::emit(&prelude_diagnostics, "prelude.idk", prelude::SOURCE) {
+            std::process::exit(1);
+        }
+        library
+    };
 
-    while node != ASTNode::End && node != ASTNode::Error {
-        ast.add_node(node.clone());
-        node = parser.parse();
+    let token_starts: Vec<usize> = tokens.iter().map(|token| token.span.start).collect();
+    // Statements with errors are left out, parsing goes on to find the rest
+    parser = Parser::new(tokens, &mut symbol_table);
+    parser.set_legacy_conditions(legacy_conditions);
+    ast = AST::new(parser.parse_program());
+    // Text the tokenizer already rejected isn't reported again, and neither
+    // is the token right after it, an open string also loses its `;`
+    let mut diagnostics: Vec<Diagnostic> = tokenizer.diagnostics().to_vec();
+    let rejected: Vec<Span> = diagnostics.iter().map(|diagnostic| diagnostic.span).collect();
+    let follow_on = |start: usize| {
+        rejected.iter().any(|span| {
+            let next: Option<usize> = token_starts.iter().copied().find(|token| *token >= span.end);
+            (span.start..span.end.max(span.start + 1)).contains(&start) || next == Some(start)
+        })
+    };
+    diagnostics.extend(
+        parser
+            .diagnostics()
+            .iter()
+            .filter(|diagnostic| !follow_on(diagnostic.span.start))
+            .cloned(),
+    );
+    // Spans point into the preprocessed text, messages show the file as written
+    for diagnostic in &mut diagnostics {
+        diagnostic.span = preprocessor::original_span(&source_code, &processed, diagnostic.span);
+        for label in &mut diagnostic.labels {
+            label.span = preprocessor::original_span(&source_code, &processed, label.span);
+        }
     }
-    if node == ASTNode::Error {
-        ast.add_node(ASTNode::Error);
-        println!("Code has errors! (nodes done: {})", ast.get_nodes().len());
-    } else {
-        ast.bttr_transform_ast();
+    if diagnostic::emit(&diagnostics, source_file_path, &source_code) {
+        std::process::exit(1);
     }
-    for n in ast.get_nodes() {
-        println!("{:?}", n);
+    ast.link(library);
+    if dump {
+        for n in ast.get_nodes() {
+            println!("{:?}", n);
+        }
+        symbol_table.print_table();
     }
 
-    symbol_table.print_table();
-
-    assembler = Assembler::new(ast, &mut symbol_table);
+    assembler = Assembler::new(ast, &mut symbol_table, source_file_path);
+    assembler.set_bounds_check(bounds_check);
+    assembler.set_checked_arithmetic(checked_arithmetic);
     assembler.generate();
 
     Ok(())
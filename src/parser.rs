@@ -1,462 +1,1882 @@
-use std::collections::HashMap;
-use std::env::var;
-use std::iter::Peekable;
-use std::u8;
-use std::vec::IntoIter;
-
-use crate::ast::ASTNode;
-use crate::fsm::FiniteStateMachine;
-use crate::symbol_table::{self, SymbolTable};
-use crate::token::{Token, TokenType};
+use crate::ast::{asm_references, ASTNode, Spanned};
+use crate::builtins;
+use crate::diagnostic::{self, Diagnostic};
+use crate::symbol_table::{reference_arity, reference_type, SymbolTable};
+use crate::token::{Span, Token, TokenType};
+use crate::tokenizer::MIN_MAGNITUDE;
 
+// Recursive descent parser. Statements are parsed one at a time and blocks
+// are nested as they are read, expressions go through `parse_binary`, a
+// precedence climbing (Pratt) loop.
 #[derive(Debug)]
 pub struct Parser<'a> {
-    tokens: Peekable<IntoIter<Token>>,
-    automata: FiniteStateMachine,
+    tokens: Vec<Token>,
+    pos: usize,
     symbol_table: &'a mut SymbolTable,
+    // Labels and starts of the loops enclosing the current statement,
+    // innermost last
+    loops: Vec<(Option<String>, Span)>,
+    // Closing keywords of the blocks being parsed, innermost last
+    blocks: Vec<TokenType>,
+    diagnostics: Vec<Diagnostic>,
+    // Set by a syntax error, the rest of the statement gets skipped
+    panicking: bool,
+    // Conditions written `lhs; op rhs;` as before comparisons were operators
+    legacy_conditions: bool,
+}
+
+// Keywords that start or end a block or a declaration, parsing resumes at
+// them after an error
+const SYNC_KEYWORDS: [TokenType; 11] = [
+    TokenType::Var,
+    TokenType::If,
+    TokenType::EndIf,
+    TokenType::While,
+    TokenType::EndWhile,
+    TokenType::For,
+    TokenType::EndFor,
+    TokenType::Do,
+    TokenType::Until,
+    TokenType::Func,
+    TokenType::Endfunc,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Associativity {
+    Left,
+    Right,
+}
+
+// Binary operators with their binding power, higher binds tighter. `a - b - c`
+// is `(a - b) - c` and `a ^ b ^ c` is `a ^ (b ^ c)`.
+const BINARY_OPERATORS: [(&str, u8, Associativity); 12] = [
+    ("==", COMPARISON, Associativity::Left),
+    ("!=", COMPARISON, Associativity::Left),
+    ("<", COMPARISON, Associativity::Left),
+    (">", COMPARISON, Associativity::Left),
+    ("<=", COMPARISON, Associativity::Left),
+    (">=", COMPARISON, Associativity::Left),
+    ("+", 2, Associativity::Left),
+    ("-", 2, Associativity::Left),
+    ("*", 3, Associativity::Left),
+    ("/", 3, Associativity::Left),
+    ("%", 3, Associativity::Left),
+    ("^", 5, Associativity::Right),
+];
+
+// Comparisons bind the loosest, `a + 1 < b * 2` compares the two sums
+const COMPARISON: u8 = 1;
+
+// Prefix operators. The operand takes in the binary operators binding tighter,
+// so `-a * b` is `(-a) * b` but `-a ^ b` is `-(a ^ b)`.
+const UNARY_OPERATORS: [(&str, u8); 1] = [("-", 4)];
+
+fn binary_operator(op: &str) -> Option<(u8, Associativity)> {
+    BINARY_OPERATORS
+        .iter()
+        .find(|(name, _, _)| *name == op)
+        .map(|(_, precedence, associativity)| (*precedence, *associativity))
+}
+
+fn unary_operator(op: &str) -> Option<u8> {
+    UNARY_OPERATORS
+        .iter()
+        .find(|(name, _)| *name == op)
+        .map(|(_, precedence)| *precedence)
+}
+
+// Keyword opening the block that `closer` ends
+fn opener_of(closer: TokenType) -> TokenType {
+    match closer {
+        TokenType::EndIf => TokenType::If,
+        TokenType::EndWhile => TokenType::While,
+        TokenType::EndFor => TokenType::For,
+        TokenType::Until => TokenType::Do,
+        _ => TokenType::Func,
+    }
+}
+
+fn is_closer(ttype: TokenType) -> bool {
+    matches!(
+        ttype,
+        TokenType::EndIf | TokenType::EndWhile | TokenType::EndFor | TokenType::Until | TokenType::Endfunc
+    )
 }
 
 impl<'a> Parser<'a> {
     pub fn new(token_vec: Vec<Token>, symbol_table: &'a mut SymbolTable) -> Self {
         symbol_table.reset_level();
+        Parser::register_functions(&token_vec, symbol_table);
         Parser {
-            tokens: token_vec.into_iter().peekable(),
-            automata: FiniteStateMachine::new(),
+            tokens: token_vec,
+            pos: 0,
             symbol_table,
+            loops: Vec::new(),
+            blocks: Vec::new(),
+            diagnostics: Vec::new(),
+            panicking: false,
+            legacy_conditions: false,
         }
     }
 
-    pub fn parse(&mut self) -> ASTNode {
-        while let Some(_) = self.tokens.peek() {
-            let response = self.automata.step(self.tokens.next().unwrap());
-            if response.1 == -1 {
-                return ASTNode::Error;
-            } else if let Some(segment) = response.0 {
-                if segment[0].ttype == TokenType::Var {
-                    return Parser::parse_assignment(self, &segment);
-                } else if segment[0].ttype == TokenType::If {
-                    return Parser::parse_if(self, &segment);
-                } else if segment[0].ttype == TokenType::EndIf {
-                    self.symbol_table.down();
-                    return ASTNode::EndIf;
-                } else if segment[0].ttype == TokenType::While {
-                    return Parser::parse_while(self, &segment);
-                } else if segment[0].ttype == TokenType::EndWhile {
-                    self.symbol_table.down();
-                    return ASTNode::EndWhile;
-                } else if segment[0].ttype == TokenType::Func {
-                    return Parser::parse_func_def(&segment);
-                } else if segment[0].ttype == TokenType::Endfunc {
-                    return ASTNode::EndFunctionDef;
-                } else if segment[0].ttype == TokenType::Call {
-                    return Parser::parse_func_call(&segment);
-                } else if segment[0].ttype == TokenType::Print {
-                    return Parser::parse_print(self, &segment);
-                } else if segment[0].ttype == TokenType::Variable {
-                    return Parser::parse_assignment(self, &segment);
+    // Registers every function signature up front, so that calls can appear
+    // before the definition they refer to.
+    pub fn register_functions(tokens: &[Token], symbol_table: &mut SymbolTable) {
+        let mut i: usize = 0;
+        while i + 1 < tokens.len() {
+            if tokens[i].ttype != TokenType::Func || tokens[i + 1].ttype != TokenType::Variable {
+                i += 1;
+                continue;
+            }
+
+            let name: String = tokens[i + 1].value.clone().expect("Something wrong with func");
+            let mut params: Vec<(String, String)> = Vec::new();
+            let mut j: usize = i + 2;
+            while j < tokens.len() && tokens[j].ttype != TokenType::SemiColon {
+                if tokens[j].ttype == TokenType::Variable {
+                    params.push((tokens[j].value.clone().unwrap(), Self::param_type(tokens, j)));
                 }
+                j += 1;
             }
+
+            symbol_table.add_function(name, params);
+            i = j;
         }
+    }
 
-        ASTNode::End
+    // `name[]` parameters receive a pointer, usually to an array, and
+    // `name(N)` ones a function taking N arguments.
+    fn param_type(tokens: &[Token], i: usize) -> String {
+        match tokens.get(i + 1).map(|tk| tk.ttype) {
+            Some(TokenType::OpenArray) => "ptr".to_string(),
+            Some(TokenType::OpenBracket) => {
+                let arity: usize = tokens
+                    .get(i + 2)
+                    .and_then(|tk| tk.value.as_deref())
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(0);
+                reference_type(arity)
+            }
+            _ => "int".to_string(),
+        }
     }
 
-    fn parse_assignment(&mut self, segment: &Vec<Token>) -> ASTNode {
-        if segment[0].ttype == TokenType::Var {
-            if let Some(var_name) = &segment[1].value {
-                if segment.len() > 2 {
-                    if segment[2].ttype == TokenType::OpenArray {
-                        let mut end: usize = 0;
-                        for tk in segment {
-                            end += 1;
-                            if tk.ttype == TokenType::CloseArray {
-                                break;
-                            }
-                        }
+    // Parses the whole token stream. Statements with errors are left out and
+    // reported in `diagnostics`, parsing goes on to find the rest.
+    pub fn parse_program(&mut self) -> Vec<Spanned> {
+        let mut program: Vec<Spanned> = Vec::new();
+        while let Some(ttype) = self.peek_type() {
+            if is_closer(ttype) {
+                self.stray_closer();
+            } else if let Some(statement) = self.parse_statement() {
+                program.push(statement);
+            }
+        }
 
-                        // let aux: ASTNode = ASTNode::Array {
-                        //     arr_name: var_name.to_string(),
-                        //     index: Box::new(Self::parse_expression(
-                        //         self,
-                        //         &segment[3..end].to_vec(),
-                        //     )),
-                        // };
-
-                        let aux2: ASTNode = ASTNode::ArrayDeclaration {
-                            arr_name: var_name.to_string(),
-                            size: Box::new(Self::parse_expression(self, &segment[3..end].to_vec())),
-                        };
-
-                        if let ASTNode::ArrayDeclaration { arr_name: _, size } = aux2.clone() {
-                            match *size {
-                                ASTNode::Literal(val) => self.symbol_table.add_to_table(var_name.to_string().clone(), "arr".to_string(), val.parse().unwrap()),
-                                ASTNode::Variable(var) => print!(""),
-                                _ => println!("Index is another type of ASTNode"),
-                            }
-                        }
+        program
+    }
 
-                        
-
-                        return aux2;
-                    } else if segment[2].ttype == TokenType::AssignmentOperator {
-                        self.symbol_table
-                            .add_to_table(var_name.to_string().clone(), "int".to_string(), 0);
-                        let aux: ASTNode = ASTNode::Assignment {
-                            var_name: var_name.to_string(),
-                            expr: Box::new(Self::parse_expression(self, &segment[3..].to_vec())),
-                        };
-                        return aux;
-                    }
-                } else if segment.len() == 2 {
-                    self.symbol_table
-                        .add_to_table(var_name.to_string().clone(), "int".to_string(), 0);
-                    let aux: ASTNode = ASTNode::Assignment {
-                        var_name: var_name.to_string(),
-                        expr: Box::new(ASTNode::Literal("0".to_string())),
-                    };
-                    return aux;
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn set_legacy_conditions(&mut self, enabled: bool) {
+        self.legacy_conditions = enabled;
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_type(&self) -> Option<TokenType> {
+        self.peek_type_at(0)
+    }
+
+    fn peek_type_at(&self, offset: usize) -> Option<TokenType> {
+        self.tokens.get(self.pos + offset).map(|token| token.ttype)
+    }
+
+    fn advance(&mut self) -> Token {
+        let token: Token = self.tokens[self.pos].clone();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, ttype: TokenType) -> Option<Token> {
+        if self.peek_type() == Some(ttype) {
+            Some(self.advance())
+        } else {
+            None
+        }
+    }
+
+    // Consumes a token of type `ttype`, otherwise reports that one of
+    // `options` was expected there.
+    fn expect(&mut self, ttype: TokenType, options: &[TokenType]) -> Option<Token> {
+        let token: Option<Token> = self.eat(ttype);
+        if token.is_none() {
+            self.expected(options);
+        }
+        token
+    }
+
+    // From the token at `start` to the last one consumed
+    fn span_from(&self, start: usize) -> Span {
+        let end: usize = self.pos.max(start + 1) - 1;
+        self.tokens[start].span.to(self.tokens[end].span)
+    }
+
+    // Records an error and returns the node that stands for it
+    fn error(&mut self, code: &'static str, message: impl Into<String>, span: Span) -> ASTNode {
+        self.diagnostics.push(Diagnostic::error(code, message, span));
+        ASTNode::Error
+    }
+
+    // Syntax error at the current token, `expectation` being what should
+    // have been there
+    fn unexpected(&mut self, expectation: &str) -> ASTNode {
+        let (found, span): (String, Span) = match self.peek() {
+            Some(token) => (token.describe(), token.span),
+            None => (
+                "end of file".to_string(),
+                self.tokens.last().map(|token| token.span).unwrap_or_default(),
+            ),
+        };
+        self.panicking = true;
+        let message: String = format!("expected {}, found {}", expectation, found);
+        self.error(diagnostic::UNEXPECTED_TOKEN, message, span)
+    }
+
+    // Syntax error listing the tokens that could have followed the last one
+    fn expected(&mut self, options: &[TokenType]) -> ASTNode {
+        let mut expected: Vec<&str> = options.iter().map(|ttype| ttype.describe()).collect();
+        expected.sort();
+        expected.dedup();
+
+        let list: String = match expected.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+            None => "nothing".to_string(),
+        };
+        match self.pos.checked_sub(1).map(|index| self.tokens[index].describe()) {
+            Some(previous) => self.unexpected(&format!("{} after {}", list, previous)),
+            None => self.unexpected(&list),
+        }
+    }
+
+    // Skips the rest of a statement with an error, up to its `;` or up to
+    // the block keyword that shows the `;` is missing. Statements that got to
+    // their `;` are left alone unless the error was a syntax error.
+    fn recover(&mut self) {
+        let ended: bool = self.pos > 0 && self.tokens[self.pos - 1].ttype == TokenType::SemiColon;
+        if ended && !self.panicking {
+            return;
+        }
+        self.panicking = false;
+        while let Some(ttype) = self.peek_type() {
+            if SYNC_KEYWORDS.contains(&ttype) {
+                return;
+            }
+            self.pos += 1;
+            if ttype == TokenType::SemiColon {
+                return;
+            }
+        }
+    }
+
+    fn parse_statement(&mut self) -> Option<Spanned> {
+        let start: usize = self.pos;
+        let reported: usize = self.diagnostics.len();
+
+        let node: ASTNode = match self.peek_type() {
+            Some(TokenType::Var) => self.parse_var(),
+            Some(TokenType::If) => self.parse_if(),
+            Some(TokenType::While | TokenType::For | TokenType::Do) => self.parse_loop(None),
+            Some(TokenType::Break | TokenType::Continue) => self.parse_loop_jump(),
+            Some(TokenType::Func) => self.parse_func_def(),
+            Some(TokenType::KeyWord) => self.parse_return(),
+            Some(TokenType::Call) => self.parse_func_call(),
+            Some(TokenType::Print | TokenType::Write) => self.parse_print(),
+            Some(TokenType::Exit) => self.parse_exit(),
+            Some(TokenType::Assert) => self.parse_assert(),
+            Some(TokenType::Asm) => self.parse_asm(),
+            Some(TokenType::Variable) => self.parse_name_statement(),
+            Some(TokenType::IntLiteral | TokenType::StringLiteral | TokenType::OpenBracket) => {
+                // A lone expression is computed and thrown away
+                if self.parse_binary(0) != ASTNode::Error && self.expect_end().is_some() {
+                    let span: Span = self.span_from(start);
+                    let warning: Diagnostic =
+                        Diagnostic::warning(diagnostic::NO_EFFECT, "Statement has no effect", span);
+                    self.diagnostics.push(warning);
+                    return None;
+                }
+                ASTNode::Error
+            }
+            _ => self.unexpected("a statement"),
+        };
+
+        if node != ASTNode::Error {
+            return Some(Spanned {
+                node,
+                span: self.span_from(start),
+            });
+        }
+        // Errors deep in an expression don't say what went wrong
+        if self.diagnostics.len() == reported {
+            let span: Span = self.span_from(start);
+            self.error(diagnostic::MALFORMED_STATEMENT, "Malformed statement", span);
+        }
+        self.recover();
+        None
+    }
+
+    // Statements up to the `closer` of the block opened by `opener`. Returns
+    // them and whether the closing keyword was found, the `;` after it is
+    // left to the caller.
+    fn parse_body(&mut self, closer: TokenType, opener: &Token) -> (Vec<Spanned>, bool) {
+        self.blocks.push(closer);
+        let mut content: Vec<Spanned> = Vec::new();
+        let mut closed: bool = false;
+
+        while let Some(ttype) = self.peek_type() {
+            if ttype == closer {
+                self.pos += 1;
+                closed = true;
+                break;
+            }
+            if is_closer(ttype) {
+                // Closes an outer block, so this one is missing its end
+                if self.blocks.contains(&ttype) {
+                    break;
                 }
+                self.stray_closer();
+                continue;
             }
+            if let Some(statement) = self.parse_statement() {
+                content.push(statement);
+            }
+        }
+        self.blocks.pop();
+
+        if !closed {
+            let message: String = format!(
+                "{} without a matching {}",
+                opener.ttype.describe(),
+                closer.describe()
+            );
+            self.error(diagnostic::UNCLOSED_BLOCK, message, opener.span);
+        }
+        (content, closed)
+    }
+
+    // `;` after a closing keyword
+    fn end_block(&mut self) {
+        self.expect(TokenType::SemiColon, &[TokenType::SemiColon]);
+        self.recover();
+    }
+
+    fn stray_closer(&mut self) {
+        let token: Token = self.advance();
+        let message: String = format!(
+            "{} without a matching {}",
+            token.ttype.describe(),
+            opener_of(token.ttype).describe()
+        );
+        self.error(diagnostic::MISPLACED_STATEMENT, message, token.span);
+        if token.ttype == TokenType::Until {
+            self.parse_condition();
+            self.recover();
         } else {
-            if let Some(var_name) = &segment[0].value {
-                if segment[1].ttype == TokenType::AssignmentOperator {
-                    let aux: ASTNode = ASTNode::Assignment {
-                        var_name: var_name.to_string(),
-                        expr: Box::new(Self::parse_expression(self, &segment[2..].to_vec())),
+            self.end_block();
+        }
+    }
+
+    fn parse_var(&mut self) -> ASTNode {
+        self.advance();
+        let Some(name) = self.expect(TokenType::Variable, &[TokenType::Variable]) else {
+            return ASTNode::Error;
+        };
+        let var_name: String = name.value.clone().unwrap_or_default();
+
+        match self.peek_type() {
+            Some(TokenType::SemiColon) => {
+                self.advance();
+                self.symbol_table
+                    .add_to_table(var_name.clone(), "int".to_string(), 0);
+                ASTNode::Assignment {
+                    var_name,
+                    expr: Box::new(ASTNode::Literal {
+                        value: "0".to_string(),
+                        span: name.span,
+                    }),
+                }
+            }
+            Some(TokenType::AssignmentOperator) => {
+                self.advance();
+                // Initialized with a function, the variable holds a reference
+                let expr: ASTNode = self.parse_expression();
+                let vtype: String = match self.reference_arity_of(&expr) {
+                    Some(arity) => reference_type(arity),
+                    None => "int".to_string(),
+                };
+                // Declared even when the initializer is wrong, so later uses
+                // aren't reported too
+                self.symbol_table.add_to_table(var_name.clone(), vtype, 0);
+                if expr == ASTNode::Error || self.expect_end().is_none() {
+                    return ASTNode::Error;
+                }
+                ASTNode::Assignment {
+                    var_name,
+                    expr: Box::new(expr),
+                }
+            }
+            Some(TokenType::OpenArray) => {
+                self.advance();
+                if self.eat(TokenType::CloseArray).is_some() {
+                    // `var p[]` declares a pointer, usually to memory from `alloc`
+                    self.symbol_table
+                        .add_to_table(var_name.clone(), "ptr".to_string(), 0);
+                    let expr: ASTNode = if self.eat(TokenType::AssignmentOperator).is_some() {
+                        self.parse_expression()
+                    } else {
+                        ASTNode::Literal {
+                            value: "0".to_string(),
+                            span: name.span,
+                        }
+                    };
+                    if expr == ASTNode::Error || self.expect_end().is_none() {
+                        return ASTNode::Error;
+                    }
+                    return ASTNode::Assignment {
+                        var_name,
+                        expr: Box::new(expr),
                     };
-                    return aux;
                 }
 
-                let mut eq_index = -1;
-                for tok in 0..segment.len() {
-                    if segment[tok].ttype == TokenType::AssignmentOperator {
-                        eq_index = tok as i32;
-                        break;
-                    }
+                let size: Option<Token> = self.expect(
+                    TokenType::IntLiteral,
+                    &[TokenType::IntLiteral, TokenType::CloseArray],
+                );
+                let size_span: Span = size.as_ref().map_or(name.span, |token| token.span);
+                let size: String = size.and_then(|token| token.value).unwrap_or_default();
+                if self
+                    .expect(TokenType::CloseArray, &[TokenType::CloseArray])
+                    .is_none()
+                    || self
+                        .expect(TokenType::SemiColon, &[TokenType::SemiColon])
+                        .is_none()
+                {
+                    return ASTNode::Error;
+                }
+                self.symbol_table.add_to_table(
+                    var_name.clone(),
+                    "arr".to_string(),
+                    size.parse().unwrap_or(0),
+                );
+                ASTNode::ArrayDeclaration {
+                    arr_name: var_name,
+                    size: Box::new(ASTNode::Literal {
+                        value: size,
+                        span: size_span,
+                    }),
                 }
+            }
+            _ => self.expected(&[
+                TokenType::SemiColon,
+                TokenType::AssignmentOperator,
+                TokenType::OpenArray,
+            ]),
+        }
+    }
 
-                if eq_index != -1 {
-                    // let aux: ASTNode = ASTNode::Assignment {
-                    //     var_name: Box::new(ASTNode::Array {
-                    //         arr_name: var_name.to_string(),
-                    //         index: Box::new(Self::parse_expression(
-                    //             self,
-                    //             &segment[2..eq_index as usize].to_vec(),
-                    //         )),
-                    //     }),
-                    //     expr: Box::new(Self::parse_expression(
-                    //         self,
-                    //         &segment[eq_index as usize + 1..].to_vec(),
-                    //     )),
-                    // };
-                    let aux: ASTNode = ASTNode::ArrayAssignment {
-                        arr_name: var_name.to_string(),
-                        position: Box::new(Self::parse_expression(self, &segment[2..eq_index as usize].to_vec())),
-                        value: Box::new(Self::parse_expression(self, &segment[eq_index as usize + 1..].to_vec(),))
-                    };
-                    return aux;
+    // Statements starting with a name: assignments, `arr + index = value`,
+    // calls and loop labels.
+    fn parse_name_statement(&mut self) -> ASTNode {
+        let start: usize = self.pos;
+        let name: Token = self.advance();
+        let var_name: String = name.value.clone().unwrap_or_default();
+
+        match self.peek_type() {
+            Some(TokenType::Colon) => {
+                self.advance();
+                self.parse_loop(Some(&name))
+            }
+            Some(TokenType::AssignmentOperator) => {
+                self.advance();
+                let expr: ASTNode = self.parse_expression();
+                if expr == ASTNode::Error || self.expect_end().is_none() {
+                    return ASTNode::Error;
+                }
+                let target: Option<usize> = self
+                    .symbol_table
+                    .check_table(var_name.clone())
+                    .and_then(|symbol| reference_arity(&symbol.vtype));
+                if target != self.reference_arity_of(&expr) {
+                    let message: String =
+                        format!("Assignment to {} doesn't match its function type", var_name);
+                    let span: Span = self.span_from(start);
+                    return self.error(diagnostic::TYPE_MISMATCH, message, span);
+                }
+                ASTNode::Assignment {
+                    var_name,
+                    expr: Box::new(expr),
+                }
+            }
+            Some(TokenType::OpenBracket) => {
+                let call: ASTNode = self.parse_call(&name);
+                if call == ASTNode::Error
+                    || self.expect(TokenType::SemiColon, &[TokenType::SemiColon]).is_none()
+                {
+                    return ASTNode::Error;
+                }
+                call
+            }
+            Some(TokenType::OpenArray) => {
+                let Some(position) = self.parse_subscript(&name) else {
+                    return ASTNode::Error;
+                };
+                if self
+                    .expect(TokenType::AssignmentOperator, &[TokenType::AssignmentOperator])
+                    .is_none()
+                {
+                    return ASTNode::Error;
+                }
+                let value: ASTNode = self.parse_expression();
+                if value == ASTNode::Error || self.expect_end().is_none() {
+                    return ASTNode::Error;
+                }
+                ASTNode::ArrayAssignment {
+                    arr_name: var_name,
+                    position: Box::new(position),
+                    value: Box::new(value),
+                    span: name.span,
+                }
+            }
+            Some(TokenType::BinaryOperator) => {
+                // `arr + index = value`, the operator only separates the two
+                self.advance();
+                let position: ASTNode = self.parse_expression();
+                if position == ASTNode::Error
+                    || self
+                        .expect(
+                            TokenType::AssignmentOperator,
+                            &[TokenType::AssignmentOperator, TokenType::BinaryOperator],
+                        )
+                        .is_none()
+                {
+                    return ASTNode::Error;
+                }
+                let value: ASTNode = self.parse_expression();
+                if value == ASTNode::Error || self.expect_end().is_none() {
+                    return ASTNode::Error;
+                }
+                ASTNode::ArrayAssignment {
+                    arr_name: var_name,
+                    position: Box::new(position),
+                    value: Box::new(value),
+                    span: name.span,
                 }
             }
+            _ => self.expected(&[
+                TokenType::AssignmentOperator,
+                TokenType::BinaryOperator,
+                TokenType::OpenArray,
+                TokenType::OpenBracket,
+                TokenType::Colon,
+            ]),
         }
+    }
 
-        ASTNode::Error
+    // `;` ending a statement that ends in an expression
+    fn expect_end(&mut self) -> Option<Token> {
+        self.expect(
+            TokenType::SemiColon,
+            &[TokenType::SemiColon, TokenType::BinaryOperator],
+        )
     }
 
-    fn parse_if(&mut self, segment: &Vec<Token>) -> ASTNode {
-        let mut comp_op_index: usize = 0;
+    // Condition of a block or an assert, ended by `;`. One that isn't a
+    // comparison holds when its value isn't 0.
+    fn parse_condition(&mut self) -> Option<(ASTNode, String, ASTNode)> {
+        if self.legacy_conditions {
+            return self.parse_legacy_condition();
+        }
 
-        for i in 1..segment.len() {
-            if segment[i].ttype == TokenType::ComparisonOperator {
-                comp_op_index = i;
-                break;
+        let condition: ASTNode = self.parse_expression();
+        if condition == ASTNode::Error || self.expect_end().is_none() {
+            return None;
+        }
+        if let Some(op) = self.peek().filter(|token| token.ttype == TokenType::ComparisonOperator) {
+            let message: String = format!("expected a statement, found {}", op.describe());
+            let diagnostic: Diagnostic = Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, message, op.span)
+                .with_note("conditions are written `a < b;`, pass --legacy-conditions for `a; < b;`");
+            self.diagnostics.push(diagnostic);
+            self.panicking = true;
+            return None;
+        }
+
+        match condition {
+            ASTNode::BinaryOperation { op, left, right, .. }
+                if binary_operator(&op).is_some_and(|(level, _)| level == COMPARISON) =>
+            {
+                Some((*left, op, *right))
+            }
+            value => {
+                let zero: ASTNode = ASTNode::Literal {
+                    value: "0".to_string(),
+                    span: value.span().unwrap_or_default(),
+                };
+                Some((value, "!=".to_string(), zero))
             }
         }
+    }
 
-        let left_part: &Vec<Token> = &segment[1..comp_op_index].to_vec();
-        let right_part: &Vec<Token> = &segment[(comp_op_index + 1)..segment.len()].to_vec();
+    // `lhs; op rhs;`, the `;` after the left side is part of the syntax
+    fn parse_legacy_condition(&mut self) -> Option<(ASTNode, String, ASTNode)> {
+        let left: ASTNode = self.parse_expression();
+        if left == ASTNode::Error || self.expect_end().is_none() {
+            return None;
+        }
+        let op: Token = self.expect(
+            TokenType::ComparisonOperator,
+            &[TokenType::ComparisonOperator],
+        )?;
+        let right: ASTNode = self.parse_expression();
+        if right == ASTNode::Error || self.expect_end().is_none() {
+            return None;
+        }
 
-        let left_ast: Box<ASTNode> = Box::new(Self::parse_expression(self, left_part));
-        if *left_ast == ASTNode::Error {
-            return ASTNode::Error;
+        Some((left, op.value.unwrap_or_default(), right))
+    }
+
+    fn parse_if(&mut self) -> ASTNode {
+        let keyword: Token = self.advance();
+        let condition = self.parse_condition();
+        self.recover();
+
+        self.symbol_table.up();
+        let (content, closed) = self.parse_body(TokenType::EndIf, &keyword);
+        self.symbol_table.down();
+        if closed {
+            self.end_block();
         }
-        let right_ast: Box<ASTNode> = Box::new(Self::parse_expression(self, right_part));
-        if *right_ast == ASTNode::Error {
+
+        match condition {
+            Some((first_half, comparison_op, second_half)) => ASTNode::IfOperation {
+                first_half: Box::new(first_half),
+                comparison_op,
+                second_half: Box::new(second_half),
+                content,
+            },
+            None => ASTNode::Error,
+        }
+    }
+
+    fn parse_assert(&mut self) -> ASTNode {
+        let keyword: Token = self.advance();
+        match self.parse_condition() {
+            Some((first_half, comparison_op, second_half)) => ASTNode::Assert {
+                first_half: Box::new(first_half),
+                comparison_op,
+                second_half: Box::new(second_half),
+                span: keyword.span,
+            },
+            None => ASTNode::Error,
+        }
+    }
+
+    // `while`, `for` or `do`, with the label written as `name: while ...;`
+    fn parse_loop(&mut self, label: Option<&Token>) -> ASTNode {
+        let name: Option<String> = label.and_then(|token| token.value.clone());
+        let enclosing: Option<Span> = self
+            .loops
+            .iter()
+            .find(|(used, _)| name.is_some() && *used == name)
+            .map(|(_, span)| *span);
+        // Label and keyword, what `first used here` points at
+        let head: Span = match (label, self.peek()) {
+            (Some(label), Some(keyword)) => label.span.to(keyword.span),
+            (None, Some(keyword)) => keyword.span,
+            (Some(label), None) => label.span,
+            (None, None) => Span::default(),
+        };
+
+        let duplicate: bool = enclosing.is_some();
+        if let Some(first) = enclosing {
+            let message: String = format!(
+                "Loop label {} is already used by an enclosing loop",
+                name.as_deref().unwrap_or_default()
+            );
+            let diagnostic: Diagnostic = Diagnostic::error(diagnostic::LOOP_LABEL, message, head)
+                .with_label(first, "first used here");
+            self.diagnostics.push(diagnostic);
+        }
+        let name: Option<String> = if duplicate { None } else { name };
+
+        let node: ASTNode = match self.peek_type() {
+            Some(TokenType::While) => self.parse_while(name, head),
+            Some(TokenType::For) => self.parse_for(name, head),
+            Some(TokenType::Do) => self.parse_do(name, head),
+            _ => return self.expected(&[TokenType::While, TokenType::For, TokenType::Do]),
+        };
+        if duplicate {
             return ASTNode::Error;
         }
 
+        node
+    }
+
+    // Body of a loop, with `label` visible to `break` and `continue` inside
+    fn parse_loop_body(
+        &mut self,
+        closer: TokenType,
+        keyword: &Token,
+        label: &Option<String>,
+        head: Span,
+    ) -> (Vec<Spanned>, bool) {
         self.symbol_table.up();
+        self.loops.push((label.clone(), head));
+        let body = self.parse_body(closer, keyword);
+        self.loops.pop();
+        self.symbol_table.down();
+
+        body
+    }
 
-        ASTNode::IfOperation {
-            first_half: left_ast,
-            comparison_op: segment[comp_op_index].value.clone().unwrap(),
-            second_half: right_ast,
-            content: Vec::new(),
+    fn parse_while(&mut self, label: Option<String>, head: Span) -> ASTNode {
+        let keyword: Token = self.advance();
+        let condition = self.parse_condition();
+        self.recover();
+
+        let (content, closed) = self.parse_loop_body(TokenType::EndWhile, &keyword, &label, head);
+        if closed {
+            self.end_block();
+        }
+
+        match condition {
+            Some((first_half, comparison_op, second_half)) => ASTNode::WhileOperation {
+                first_half: Box::new(first_half),
+                comparison_op,
+                second_half: Box::new(second_half),
+                content,
+                label,
+            },
+            None => ASTNode::Error,
         }
     }
 
-    fn parse_while(&mut self, segment: &Vec<Token>) -> ASTNode {
-        let mut comp_op_index: usize = 0;
+    // The body runs before the condition of `until` is checked. The condition
+    // can't see variables declared in the body.
+    fn parse_do(&mut self, label: Option<String>, head: Span) -> ASTNode {
+        let keyword: Token = self.advance();
+        self.expect(TokenType::SemiColon, &[TokenType::SemiColon]);
+        self.recover();
 
-        for i in 1..segment.len() {
-            if segment[i].ttype == TokenType::ComparisonOperator {
-                comp_op_index = i;
-                break;
+        let (content, closed) = self.parse_loop_body(TokenType::Until, &keyword, &label, head);
+        if !closed {
+            return ASTNode::Error;
+        }
+
+        match self.parse_condition() {
+            Some((first_half, comparison_op, second_half)) => ASTNode::DoUntil {
+                first_half: Box::new(first_half),
+                comparison_op,
+                second_half: Box::new(second_half),
+                content,
+                label,
+            },
+            None => ASTNode::Error,
+        }
+    }
+
+    // `for i = start to end step [-]N;`. The loop variable is declared in
+    // the enclosing scope when it doesn't exist yet, so it keeps its last
+    // value after `endfor`.
+    fn parse_for(&mut self, label: Option<String>, head: Span) -> ASTNode {
+        let keyword: Token = self.advance();
+        let header: Option<(String, ASTNode, ASTNode, i64)> = self.parse_for_header();
+        self.recover();
+
+        let (content, closed) = self.parse_loop_body(TokenType::EndFor, &keyword, &label, head);
+        if closed {
+            self.end_block();
+        }
+
+        match header {
+            Some((var_name, from, to, step)) => ASTNode::ForOperation {
+                var_name,
+                from: Box::new(from),
+                to: Box::new(to),
+                step,
+                content,
+                label,
+            },
+            None => ASTNode::Error,
+        }
+    }
+
+    fn parse_for_header(&mut self) -> Option<(String, ASTNode, ASTNode, i64)> {
+        let var: Token = self.expect(TokenType::Variable, &[TokenType::Variable])?;
+        let var_name: String = var.value.clone().unwrap_or_default();
+        self.expect(TokenType::AssignmentOperator, &[TokenType::AssignmentOperator])?;
+
+        match self.symbol_table.check_table(var_name.clone()) {
+            Some(symbol) if symbol.vtype != "int" => {
+                let message: String = format!("For loop variable {} is not an int", var_name);
+                self.error(diagnostic::TYPE_MISMATCH, message, var.span);
+                return None;
             }
+            Some(_) => {}
+            None => self
+                .symbol_table
+                .add_to_table(var_name.clone(), "int".to_string(), 0),
         }
 
-        let left_part: &Vec<Token> = &segment[1..comp_op_index].to_vec();
-        let right_part: &Vec<Token> = &segment[comp_op_index..segment.len()].to_vec();
+        let from: ASTNode = self.parse_expression();
+        if from == ASTNode::Error {
+            return None;
+        }
+        self.expect(TokenType::To, &[TokenType::To, TokenType::BinaryOperator])?;
+        let to: ASTNode = self.parse_expression();
+        if to == ASTNode::Error {
+            return None;
+        }
 
-        let left_ast: Box<ASTNode> = Box::new(Self::parse_expression(self, left_part));
-        if *left_ast == ASTNode::Error {
-            println!("CCCCCCCCCCCCCCCCCCCCCCCCCC");
-            return ASTNode::Error;
+        if self.eat(TokenType::Step).is_none() {
+            self.expect(
+                TokenType::SemiColon,
+                &[TokenType::SemiColon, TokenType::Step, TokenType::BinaryOperator],
+            )?;
+            return Some((var_name, from, to, 1));
         }
-        let right_ast: Box<ASTNode> = Box::new(Self::parse_expression(self, right_part));
-        if *right_ast == ASTNode::Error {
-            println!("DDDDDDDDDDDDDDDDDDDDDDDDDDD");
+        let sign: Option<Token> = self.eat(TokenType::BinaryOperator);
+        let digits: Token = self.expect(
+            TokenType::IntLiteral,
+            &[TokenType::IntLiteral, TokenType::BinaryOperator],
+        )?;
+        if let Some(sign) = &sign {
+            if sign.value.as_deref() != Some("-") {
+                let span: Span = sign.span.to(digits.span);
+                self.error(diagnostic::BAD_FOR_STEP, "Bad step of for loop", span);
+                return None;
+            }
+        }
+        let value: i64 = digits.value.as_deref().unwrap_or("0").parse().unwrap_or(0);
+        let step: i64 = if sign.is_some() { -value } else { value };
+        if step == 0 {
+            self.error(diagnostic::BAD_FOR_STEP, "Step of for loop can't be 0", digits.span);
+            return None;
+        }
+        self.expect(TokenType::SemiColon, &[TokenType::SemiColon])?;
+
+        Some((var_name, from, to, step))
+    }
+
+    // `break;` and `continue;` leave or restart the innermost loop, or the
+    // enclosing loop with the given label.
+    fn parse_loop_jump(&mut self) -> ASTNode {
+        let keyword_token: Token = self.advance();
+        let keyword: &str = if keyword_token.ttype == TokenType::Break { "break" } else { "continue" };
+        let label_token: Option<Token> = self.eat(TokenType::Variable);
+        let label: Option<String> = label_token.as_ref().and_then(|tk| tk.value.clone());
+        let options: &[TokenType] = if label.is_some() {
+            &[TokenType::SemiColon]
+        } else {
+            &[TokenType::SemiColon, TokenType::Variable]
+        };
+        if self.expect(TokenType::SemiColon, options).is_none() {
             return ASTNode::Error;
         }
 
+        if self.loops.is_empty() {
+            let message: String = format!("{} outside of a loop", keyword);
+            return self.error(diagnostic::MISPLACED_STATEMENT, message, keyword_token.span);
+        }
+        if let Some(label_token) = &label_token {
+            if !self.loops.iter().any(|(name, _)| *name == label) {
+                let message: String = format!(
+                    "{} names no enclosing loop {}",
+                    keyword,
+                    label.as_deref().unwrap_or_default()
+                );
+                return self.error(diagnostic::LOOP_LABEL, message, label_token.span);
+            }
+        }
+
+        if keyword_token.ttype == TokenType::Break {
+            ASTNode::Break(label)
+        } else {
+            ASTNode::Continue(label)
+        }
+    }
+
+    fn parse_func_def(&mut self) -> ASTNode {
+        let keyword: Token = self.advance();
+        let header: Option<(Token, Vec<(String, String)>)> = self.parse_func_header();
+        self.recover();
+
+        // The body is parsed either way, so its `endfunc` isn't reported too
+        let nested: bool = self.symbol_table.current_function().is_some();
+        let mut valid: bool = header.is_some();
+        if let Some((name, _)) = &header {
+            let func_name: String = name.value.clone().unwrap_or_default();
+            if nested {
+                let message: String =
+                    format!("Function {} is defined inside another function", func_name);
+                self.error(diagnostic::MISPLACED_STATEMENT, message, name.span);
+                valid = false;
+            } else if !self.blocks.is_empty() {
+                let message: String = format!("Function {} is defined inside a block", func_name);
+                let error: Diagnostic = Diagnostic::error(diagnostic::MISPLACED_STATEMENT, message, name.span)
+                    .with_note("functions can only be defined at the top level");
+                self.diagnostics.push(error);
+                valid = false;
+            } else if builtins::arity(&func_name).is_some() {
+                let message: String = format!("Function {} has the name of a builtin", func_name);
+                self.error(diagnostic::BUILTIN_NAME, message, name.span);
+                valid = false;
+            }
+        }
+
+        let name: String = header
+            .as_ref()
+            .and_then(|(name, _)| name.value.clone())
+            .unwrap_or_default();
+        if !nested {
+            self.symbol_table.enter_function(name.clone());
+        }
         self.symbol_table.up();
+        let mut params: Vec<String> = Vec::new();
+        for (param, vtype) in header.map(|(_, params)| params).unwrap_or_default() {
+            self.symbol_table.add_to_table(param.clone(), vtype, 0);
+            params.push(param);
+        }
+
+        // Loops around the definition can't be left from inside the body
+        let loops: Vec<(Option<String>, Span)> = std::mem::take(&mut self.loops);
+        let (content, closed) = self.parse_body(TokenType::Endfunc, &keyword);
+        self.loops = loops;
+        self.symbol_table.down();
+        if !nested {
+            self.symbol_table.leave_function();
+        }
+        if closed {
+            self.end_block();
+        }
 
-        ASTNode::WhileOperation {
-            first_half: left_ast,
-            comparison_op: segment[comp_op_index].value.clone().unwrap(),
-            second_half: right_ast,
-            content: Vec::new(),
+        if !valid {
+            return ASTNode::Error;
+        }
+        ASTNode::FunctionDef {
+            name,
+            parameters: if params.is_empty() { None } else { Some(params) },
+            content,
         }
     }
 
-    //
+    // `func name;` or `func name: a, b[], f(2);`
+    fn parse_func_header(&mut self) -> Option<(Token, Vec<(String, String)>)> {
+        let name: Token = self.expect(TokenType::Variable, &[TokenType::Variable])?;
+        let mut params: Vec<(String, String)> = Vec::new();
+        if self.eat(TokenType::Colon).is_none() {
+            self.expect(TokenType::SemiColon, &[TokenType::SemiColon, TokenType::Colon])?;
+            return Some((name, params));
+        }
 
-    fn parse_expression(&mut self, segment: &Vec<Token>) -> ASTNode {
-        let rpn_tokens: Vec<Token> = Parser::convert_to_rpn(&segment);
-        println!("RPN: {:?}", rpn_tokens);
-        let mut intermediate_stack: Vec<ASTNode> = vec![];
+        loop {
+            let param: Token = self.expect(TokenType::Variable, &[TokenType::Variable])?;
+            let vtype: String = if self.eat(TokenType::OpenArray).is_some() {
+                self.expect(TokenType::CloseArray, &[TokenType::CloseArray])?;
+                "ptr".to_string()
+            } else if self.eat(TokenType::OpenBracket).is_some() {
+                let arity: Token = self.expect(TokenType::IntLiteral, &[TokenType::IntLiteral])?;
+                self.expect(TokenType::CloseBracket, &[TokenType::CloseBracket])?;
+                reference_type(arity.value.as_deref().unwrap_or("0").parse().unwrap_or(0))
+            } else {
+                "int".to_string()
+            };
+            params.push((param.value.unwrap_or_default(), vtype));
 
-        let mut i = 0;
-        while i < rpn_tokens.len() {
-            let token = &rpn_tokens[i];
+            if self.eat(TokenType::Comma).is_none() {
+                break;
+            }
+        }
+        self.expect(
+            TokenType::SemiColon,
+            &[TokenType::SemiColon, TokenType::Comma],
+        )?;
 
-            match token.ttype {
-                TokenType::IntLiteral => {
-                    intermediate_stack.push(ASTNode::Literal(
-                        token.value.clone().expect("Missing literal value"),
-                    ));
-                    i += 1;
-                }
-                TokenType::Variable => {
-                    let var_name = token.value.clone().expect("Missing variable name");
-                    if let Some(symb) = self.symbol_table.check_table(var_name.clone()) {
-                        if symb.vtype == "int" {
-                            intermediate_stack.push(ASTNode::Variable(var_name.clone()));
-                        } else if symb.vtype == "arr" {
-                            return ASTNode::Array {
-                                arr_name: symb.vname.clone(),
-                                index: Box::new(Self::parse_expression(
-                                    self,
-                                    &segment[2..].to_vec(),
-                                )),
-                            };
-                        }
-                    }
-                    i += 1;
-
-                    // Check if next token is OpenArray
-                    // if i + 1 < rpn_tokens.len() && rpn_tokens[i + 1].ttype == TokenType::OpenArray {
-                    //     // Find matching CloseArray
-                    //     let mut depth = 1;
-                    //     let mut j = i + 2;
-
-                    //     while j < rpn_tokens.len() && depth > 0 {
-                    //         match rpn_tokens[j].ttype {
-                    //             TokenType::OpenArray => depth += 1,
-                    //             TokenType::CloseArray => depth -= 1,
-                    //             _ => {}
-                    //         }
-                    //         j += 1;
-                    //     }
-
-                    //     if depth != 0 {
-                    //         return ASTNode::Error; // Mismatched brackets
-                    //     }
-
-                    //     let index_tokens = &rpn_tokens[i + 2..j - 1];
-                    //     let index_expr = self.parse_expression(&index_tokens.to_vec());
-
-                    //     intermediate_stack.push(ASTNode::Array {
-                    //         arr_name: var_name,
-                    //         index: Box::new(index_expr),
-                    //     });
-
-                    //     i = j; // move past ]
-                    // } else {
-                    //     intermediate_stack.push(ASTNode::Variable(var_name));
-                    //     i += 1;
-                    // }
+        Some((name, params))
+    }
+
+    fn parse_return(&mut self) -> ASTNode {
+        let keyword: Token = self.advance();
+        let value: Option<Box<ASTNode>> = if self.eat(TokenType::SemiColon).is_some() {
+            None
+        } else {
+            let value: ASTNode = self.parse_expression();
+            if value == ASTNode::Error || self.expect_end().is_none() {
+                return ASTNode::Error;
+            }
+            Some(Box::new(value))
+        };
+
+        if self.symbol_table.current_function().is_none() {
+            let message: &str = "return outside of a function";
+            return self.error(diagnostic::MISPLACED_STATEMENT, message, keyword.span);
+        }
+        ASTNode::Return(value)
+    }
+
+    // `call name;` or `call name: param = value, ...;`
+    fn parse_func_call(&mut self) -> ASTNode {
+        let start: usize = self.pos;
+        self.advance();
+        let Some(name) = self.expect(TokenType::Variable, &[TokenType::Variable]) else {
+            return ASTNode::Error;
+        };
+
+        let mut parameters: Option<Vec<(String, ASTNode)>> = None;
+        if self.eat(TokenType::Colon).is_some() {
+            let mut list: Vec<(String, ASTNode)> = Vec::new();
+            loop {
+                let Some(param) = self.expect(TokenType::Variable, &[TokenType::Variable]) else {
+                    return ASTNode::Error;
+                };
+                if self
+                    .expect(TokenType::AssignmentOperator, &[TokenType::AssignmentOperator])
+                    .is_none()
+                {
+                    return ASTNode::Error;
                 }
-                TokenType::BinaryOperator => {
-                    if let (Some(right), Some(left)) =
-                        (intermediate_stack.pop(), intermediate_stack.pop())
-                    {
-                        let aux = ASTNode::BinaryOperation {
-                            op: token.value.clone().expect("Missing operator value"),
-                            left: Box::new(left),
-                            right: Box::new(right),
-                        };
-                        intermediate_stack.push(aux);
-                    } else {
-                        return ASTNode::Error;
+                let value: ASTNode = match self.peek_type() {
+                    Some(TokenType::Variable) => {
+                        let token: Token = self.advance();
+                        self.variable(&token)
                     }
-                    i += 1;
+                    Some(TokenType::IntLiteral | TokenType::StringLiteral) => self.parse_operand(),
+                    _ => return self.expected(&[TokenType::Variable, TokenType::IntLiteral]),
+                };
+                if value == ASTNode::Error {
+                    return ASTNode::Error;
                 }
-                _ => {
-                    return ASTNode::Error; // Unexpected token
+                list.push((param.value.unwrap_or_default(), value));
+                if self.eat(TokenType::Comma).is_none() {
+                    break;
                 }
             }
+            parameters = Some(list);
         }
-
-        if intermediate_stack.len() != 1 {
-            ASTNode::Error
+        let options: &[TokenType] = if parameters.is_some() {
+            &[TokenType::SemiColon, TokenType::Comma]
         } else {
-            intermediate_stack.pop().unwrap()
+            &[TokenType::SemiColon, TokenType::Colon]
+        };
+        if self.expect(TokenType::SemiColon, options).is_none() {
+            return ASTNode::Error;
         }
+
+        let span: Span = self.span_from(start);
+        self.check_func_call(&name, parameters.unwrap_or_default(), span)
     }
 
-    fn convert_to_rpn(tokens: &Vec<Token>) -> Vec<Token> {
-        let precedence: HashMap<String, u8> = HashMap::from([
-            ("(".to_string(), 10),
-            (")".to_string(), 10),
-            ("+".to_string(), 1),
-            ("-".to_string(), 1),
-            ("*".to_string(), 2),
-            ("/".to_string(), 2),
-            ("^".to_string(), 3),
-        ]);
+    // The `call name: param = value, ...;` form names its arguments, they
+    // have to match the parameters of the definition. It becomes a plain call
+    // with the arguments in the order of the definition.
+    fn check_func_call(&mut self, name: &Token, given: Vec<(String, ASTNode)>, span: Span) -> ASTNode {
+        let func_name: String = name.value.clone().unwrap_or_default();
+        let func = match self.symbol_table.function(&func_name) {
+            Some(func) => func.clone(),
+            None => {
+                let message: String = format!("Call to undefined function {}", func_name);
+                return self.error(diagnostic::UNDEFINED_FUNCTION, message, name.span);
+            }
+        };
+        let all_given: bool = func
+            .params
+            .iter()
+            .all(|(param, _)| given.iter().any(|(given, _)| given == param));
+        let all_known: bool = given
+            .iter()
+            .all(|(param, _)| func.params.iter().any(|(known, _)| known == param));
+        if given.len() != func.params.len() || !all_given || !all_known {
+            let message: String = format!("Arguments of call {} don't match its parameters", func_name);
+            return self.error(diagnostic::ARGUMENT_MISMATCH, message, span);
+        }
 
-        let mut result: Vec<Token> = vec![];
+        let mut given: Vec<(String, ASTNode)> = given;
+        let args: Vec<ASTNode> = func
+            .params
+            .iter()
+            .filter_map(|(param, _)| {
+                let index: usize = given.iter().position(|(given, _)| given == param)?;
+                Some(given.swap_remove(index).1)
+            })
+            .collect();
+        if !self.check_reference_args(&func_name, &args, span) {
+            return ASTNode::Error;
+        }
+        ASTNode::Call {
+            name: func_name,
+            args,
+            span,
+        }
+    }
 
-        let mut operator_stack: Vec<Token> = vec![];
+    fn parse_print(&mut self) -> ASTNode {
+        let keyword: Token = self.advance();
+        let mut args: Vec<(u32, Box<ASTNode>)> = Vec::new();
 
-        for token in tokens {
-            if token.ttype == TokenType::BinaryOperator {
-                while let Some(top_of_stack) = operator_stack.last() {
-                    let token_val = token.value.as_deref().expect("Weird token!");
-                    let top_stack_val = top_of_stack.value.as_deref().expect("Weird token 2!");
+        loop {
+            let base: u32 = self.print_format();
+            let value: ASTNode = self.parse_expression();
+            if value == ASTNode::Error {
+                return ASTNode::Error;
+            }
+            if base != 10
+                && self
+                    .expect(TokenType::CloseBracket, &[TokenType::CloseBracket, TokenType::BinaryOperator])
+                    .is_none()
+            {
+                return ASTNode::Error;
+            }
+            args.push((base, Box::new(value)));
 
-                    if precedence[token_val] < precedence[top_stack_val] && top_stack_val != "(" {
-                        result.push(
-                            operator_stack
-                                .pop()
-                                .expect("something very wrong with operator stack!"),
-                        );
-                    } else {
-                        break;
-                    }
-                }
+            if self.eat(TokenType::Comma).is_none() {
+                break;
+            }
+        }
+        if self
+            .expect(
+                TokenType::SemiColon,
+                &[TokenType::SemiColon, TokenType::Comma, TokenType::BinaryOperator],
+            )
+            .is_none()
+        {
+            return ASTNode::Error;
+        }
 
-                operator_stack.push(token.clone());
-            } else if token.ttype == TokenType::IntLiteral || token.ttype == TokenType::Variable {
-                result.push(token.clone());
-            } else if token.ttype == TokenType::OpenBracket {
-                operator_stack.push(token.clone());
-            } else if token.ttype == TokenType::CloseBracket {
-                while let Some(top_of_stack) = operator_stack.last() {
-                    if top_of_stack.ttype != TokenType::OpenBracket {
-                        result.push(
-                            operator_stack
-                                .pop()
-                                .expect("Something wrong with operator stack!"),
-                        );
-                    } else {
-                        operator_stack.pop().expect("IDK");
-                        break;
-                    }
+        ASTNode::Print {
+            args,
+            newline: keyword.ttype == TokenType::Print,
+        }
+    }
+
+    // Recognizes `hex(expr)` and `bin(expr)` making up a whole print item and
+    // consumes up to the wrapped expression. Returns the base to print in.
+    fn print_format(&mut self) -> u32 {
+        let name: Option<String> = self.peek().and_then(|token| token.value.clone());
+        let base: u32 = match name.as_deref() {
+            Some("hex") => 16,
+            Some("bin") => 2,
+            _ => return 10,
+        };
+        // A function or variable of that name is called as usual
+        let name: String = name.unwrap_or_default();
+        if self.symbol_table.check_table(name.clone()).is_some() || self.symbol_table.function(&name).is_some() {
+            return 10;
+        }
+        if self.peek_type() != Some(TokenType::Variable)
+            || self.peek_type_at(1) != Some(TokenType::OpenBracket)
+            || self.peek_type_at(2) == Some(TokenType::CloseBracket)
+        {
+            return 10;
+        }
+
+        // The bracket after the name has to close the item, otherwise this is
+        // something like `hex(a) + (b)`.
+        let mut depth: i32 = 0;
+        let mut close: usize = self.pos + 1;
+        while let Some(token) = self.tokens.get(close) {
+            match token.ttype {
+                TokenType::OpenBracket => depth += 1,
+                TokenType::CloseBracket => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            close += 1;
+        }
+        match self.tokens.get(close + 1).map(|token| token.ttype) {
+            Some(TokenType::Comma | TokenType::SemiColon) | None => {
+                self.pos += 2;
+                base
+            }
+            _ => 10,
+        }
+    }
+
+    fn parse_exit(&mut self) -> ASTNode {
+        self.advance();
+        let code: ASTNode = self.parse_expression();
+        if code == ASTNode::Error || self.expect_end().is_none() {
+            return ASTNode::Error;
+        }
+
+        ASTNode::Exit(Box::new(code))
+    }
+
+    // Lines are kept verbatim, only the `{name}` references are checked here
+    fn parse_asm(&mut self) -> ASTNode {
+        let token: Token = self.advance();
+        if self
+            .expect(TokenType::SemiColon, &[TokenType::SemiColon])
+            .is_none()
+        {
+            return ASTNode::Error;
+        }
+
+        let body: String = token.value.clone().unwrap_or_default();
+        for name in asm_references(&body) {
+            if self.symbol_table.check_table(name.to_string()).is_none() {
+                let message: String = format!("Undefined variable {{{}}} in asm block", name);
+                return self.error(diagnostic::UNDEFINED_VARIABLE, message, token.span);
+            }
+        }
+
+        ASTNode::InlineAsm(body.lines().map(|line| line.to_string()).collect())
+    }
+
+    fn parse_expression(&mut self) -> ASTNode {
+        self.parse_indexed(0)
+    }
+
+    // Like `parse_binary`, but an operand starting with an array or pointer
+    // followed by an operator reads an element, `arr + i + 1` is element
+    // `i + 1` of arr. The element index stops at comparisons.
+    fn parse_indexed(&mut self, min: u8) -> ASTNode {
+        if self.peek_type() == Some(TokenType::Variable)
+            && self.peek_type_at(1) == Some(TokenType::BinaryOperator)
+        {
+            let token: Token = self.tokens[self.pos].clone();
+            let name: String = token.value.clone().unwrap_or_default();
+            let indexed: bool = self
+                .symbol_table
+                .check_table(name.clone())
+                .is_some_and(|symbol| symbol.vtype == "arr" || symbol.vtype == "ptr");
+            if indexed {
+                self.pos += 2;
+                let index: ASTNode = self.parse_indexed(COMPARISON + 1);
+                if index == ASTNode::Error {
+                    return ASTNode::Error;
                 }
+                let element: ASTNode = ASTNode::Array {
+                    arr_name: name,
+                    index: Box::new(index),
+                    span: token.span,
+                };
+                return self.parse_operators(element, min);
             }
         }
 
-        while let Some(_) = operator_stack.last() {
-            result.push(
-                operator_stack
-                    .pop()
-                    .expect("Something very wrong with stack 2!"),
-            );
+        self.parse_binary(min)
+    }
+
+    // Operands joined by operators binding at least as tight as `min`
+    fn parse_binary(&mut self, min: u8) -> ASTNode {
+        let left: ASTNode = self.parse_operand();
+        if left == ASTNode::Error {
+            return ASTNode::Error;
         }
+        self.parse_operators(left, min)
+    }
 
-        result
+    // The operators following `left` and their right operands
+    fn parse_operators(&mut self, mut left: ASTNode, min: u8) -> ASTNode {
+        while matches!(
+            self.peek_type(),
+            Some(TokenType::BinaryOperator | TokenType::ComparisonOperator)
+        ) {
+            let op: String = self.peek().and_then(|token| token.value.clone()).unwrap_or_default();
+            let Some((level, associativity)) = binary_operator(&op) else {
+                break;
+            };
+            if level < min {
+                break;
+            }
+            let op_token: Token = self.advance();
+            // The right side of a left associative operator stops at the
+            // next operator of the same level
+            let next: u8 = match associativity {
+                Associativity::Left => level + 1,
+                Associativity::Right => level,
+            };
+            let right: ASTNode = if level == COMPARISON {
+                self.parse_indexed(next)
+            } else {
+                self.parse_binary(next)
+            };
+            if right == ASTNode::Error {
+                return ASTNode::Error;
+            }
+            left = ASTNode::BinaryOperation {
+                op,
+                left: Box::new(left),
+                right: Box::new(right),
+                span: op_token.span,
+            };
+        }
+
+        left
     }
 
-    fn parse_func_def(segment: &Vec<Token>) -> ASTNode {
-        if segment.len() == 3 {
-            return ASTNode::FunctionDef {
-                name: segment[1].value.clone().expect("Something wrong with func"),
-                parameters: None,
+    fn parse_operand(&mut self) -> ASTNode {
+        let prefix: Option<u8> = match self.peek() {
+            Some(token) if token.ttype == TokenType::BinaryOperator => {
+                token.value.as_deref().and_then(unary_operator)
+            }
+            _ => None,
+        };
+        if let Some(level) = prefix {
+            let op_token: Token = self.advance();
+            // The one literal that only fits negated
+            let magnitude: String = MIN_MAGNITUDE.to_string();
+            if op_token.value.as_deref() == Some("-")
+                && self.peek().is_some_and(|token| {
+                    token.ttype == TokenType::IntLiteral && token.value.as_ref() == Some(&magnitude)
+                })
+            {
+                let literal: Token = self.advance();
+                return ASTNode::Literal {
+                    value: format!("-{}", magnitude),
+                    span: op_token.span.to(literal.span),
+                };
+            }
+            let operand: ASTNode = self.parse_binary(level);
+            if operand == ASTNode::Error {
+                return ASTNode::Error;
+            }
+            return ASTNode::UnaryOperation {
+                op: op_token.value.unwrap_or_default(),
+                operand: Box::new(operand),
+                span: op_token.span,
             };
-        } else {
-            let mut params: Option<Vec<String>> = Some(Vec::new());
-            for i in 3..segment.len() {
-                if segment[i].ttype == TokenType::Comma {
-                    continue;
+        }
+
+        match self.peek_type() {
+            Some(TokenType::IntLiteral) => {
+                let token: Token = self.advance();
+                let value: String = token.value.expect("Missing literal value");
+                if value == MIN_MAGNITUDE.to_string() {
+                    let message: String = format!("Number {} is larger than {}", value, i32::MAX);
+                    let error: Diagnostic = Diagnostic::error(diagnostic::INVALID_LITERAL, message, token.span)
+                        .with_note(format!("-{} can only be written negated", value));
+                    self.diagnostics.push(error);
+                    return ASTNode::Error;
                 }
-                if let Some(ref mut vec) = params {
-                    vec.push(
-                        segment[i]
-                            .value
-                            .clone()
-                            .expect("Something wrong with func def"),
-                    );
+                ASTNode::Literal {
+                    value,
+                    span: token.span,
                 }
             }
-            return ASTNode::FunctionDef {
-                name: segment[1].value.clone().expect("Something wrong with func"),
-                parameters: params,
-            };
+            Some(TokenType::StringLiteral) => {
+                let token: Token = self.advance();
+                ASTNode::StringLiteral {
+                    value: token.value.expect("Missing literal value"),
+                    span: token.span,
+                }
+            }
+            Some(TokenType::OpenBracket) => {
+                self.advance();
+                let inner: ASTNode = self.parse_binary(0);
+                if inner == ASTNode::Error
+                    || self
+                        .expect(
+                            TokenType::CloseBracket,
+                            &[TokenType::CloseBracket, TokenType::BinaryOperator],
+                        )
+                        .is_none()
+                {
+                    return ASTNode::Error;
+                }
+                inner
+            }
+            Some(TokenType::Variable) => {
+                let token: Token = self.advance();
+                match self.peek_type() {
+                    Some(TokenType::OpenBracket) => self.parse_call(&token),
+                    Some(TokenType::OpenArray) => match self.parse_subscript(&token) {
+                        Some(index) => ASTNode::Array {
+                            arr_name: token.value.unwrap_or_default(),
+                            index: Box::new(index),
+                            span: token.span,
+                        },
+                        None => ASTNode::Error,
+                    },
+                    _ => self.variable(&token),
+                }
+            }
+            _ => self.expected(&[
+                TokenType::OpenBracket,
+                TokenType::IntLiteral,
+                TokenType::StringLiteral,
+                TokenType::Variable,
+            ]),
         }
     }
 
-    fn parse_func_call(segment: &Vec<Token>) -> ASTNode {
-        if segment.len() == 2 {
-            return ASTNode::FuntionCall {
-                name: segment[1].value.clone().expect("Something wrong with func"),
-                parameters: None,
-            };
-        } else {
-            let mut params: Option<Vec<(String, String)>> = Some(Vec::new());
-            for i in (3..segment.len()).step_by(4) {
-                if let Some(ref mut vec) = params {
-                    if segment.len() - 4 == i {
-                        break;
-                    } else {
-                        vec.push((
-                            segment[i]
-                                .value
-                                .clone()
-                                .expect("Something wrong with func def"),
-                            segment[i + 2]
-                                .value
-                                .clone()
-                                .expect("Something wrong with func def"),
-                        ));
-                    }
+    // `[index]` after the name of an array or pointer
+    fn parse_subscript(&mut self, token: &Token) -> Option<ASTNode> {
+        let name: String = token.value.clone().unwrap_or_default();
+        let vtype: Option<String> = self
+            .symbol_table
+            .check_table(name.clone())
+            .map(|symbol| symbol.vtype.clone());
+        match vtype.as_deref() {
+            Some("arr" | "ptr") => {}
+            Some(_) => {
+                let message: String = format!("{} is not an array or a pointer", name);
+                self.error(diagnostic::TYPE_MISMATCH, message, token.span);
+                return None;
+            }
+            None => {
+                let message: String = format!("Undefined variable {}", name);
+                self.error(diagnostic::UNDEFINED_VARIABLE, message, token.span);
+                return None;
+            }
+        }
+
+        self.advance();
+        let index: ASTNode = self.parse_expression();
+        if index == ASTNode::Error {
+            return None;
+        }
+        self.expect(
+            TokenType::CloseArray,
+            &[TokenType::CloseArray, TokenType::BinaryOperator],
+        )?;
+        Some(index)
+    }
+
+    // A name used as a value
+    fn variable(&mut self, token: &Token) -> ASTNode {
+        let name: String = token.value.clone().expect("Missing variable name");
+        match self.symbol_table.check_table(name.clone()) {
+            // A bare array name stands for its address
+            Some(symbol) if symbol.vtype == "arr" => ASTNode::Address {
+                name,
+                span: token.span,
+            },
+            Some(_) => ASTNode::Variable {
+                name,
+                span: token.span,
+            },
+            // A bare function name stands for a reference to it
+            None if self.symbol_table.function(&name).is_some() => ASTNode::FunctionRef {
+                name,
+                span: token.span,
+            },
+            None => {
+                let message: String = format!("Undefined variable {}", name);
+                self.error(diagnostic::UNDEFINED_VARIABLE, message, token.span)
+            }
+        }
+    }
+
+    // `name(args)` with the `(` next
+    fn parse_call(&mut self, token: &Token) -> ASTNode {
+        let name: String = token.value.clone().unwrap_or_default();
+        // Variables holding a function reference shadow functions
+        let reference: Option<usize> = self
+            .symbol_table
+            .check_table(name.clone())
+            .and_then(|symbol| reference_arity(&symbol.vtype));
+        let arity: Option<usize> = reference
+            .or_else(|| builtins::arity(&name))
+            .or_else(|| self.symbol_table.function(&name).map(|func| func.params.len()));
+        let Some(arity) = arity else {
+            let message: String = format!("Call to undefined function {}", name);
+            return self.error(diagnostic::UNDEFINED_FUNCTION, message, token.span);
+        };
+
+        self.advance();
+        let mut args: Vec<ASTNode> = Vec::new();
+        if self.peek_type() != Some(TokenType::CloseBracket) {
+            loop {
+                let arg: ASTNode = self.parse_expression();
+                if arg == ASTNode::Error {
+                    return ASTNode::Error;
+                }
+                args.push(arg);
+                if self.eat(TokenType::Comma).is_none() {
+                    break;
                 }
             }
-            return ASTNode::FuntionCall {
-                name: segment[1].value.clone().expect("Something wrong with func"),
-                parameters: params,
+        }
+        let close: Token = match self.expect(
+            TokenType::CloseBracket,
+            &[TokenType::CloseBracket, TokenType::Comma, TokenType::BinaryOperator],
+        ) {
+            Some(close) => close,
+            None => return ASTNode::Error,
+        };
+
+        if args.len() != arity {
+            let message: String = format!(
+                "Function {} takes {} argument(s), got {}",
+                name,
+                arity,
+                args.len()
+            );
+            let mut diagnostic: Diagnostic =
+                Diagnostic::error(diagnostic::ARGUMENT_MISMATCH, message, token.span.to(close.span));
+            if let Some(func) = self.symbol_table.function(&name) {
+                let params: Vec<&str> =
+                    func.params.iter().map(|(param, _)| param.as_str()).collect();
+                let signature: String = if params.is_empty() {
+                    format!("func {}", name)
+                } else {
+                    format!("func {}: {}", name, params.join(", "))
+                };
+                diagnostic = diagnostic.with_note(format!("{} is declared as {}", name, signature));
+            }
+            self.diagnostics.push(diagnostic);
+            return ASTNode::Error;
+        }
+
+        let span: Span = token.span.to(close.span);
+        if reference.is_some() {
+            ASTNode::IndirectCall {
+                target: name,
+                args,
+                span,
+            }
+        } else if builtins::arity(&name).is_some() {
+            ASTNode::BuiltinCall { name, args, span }
+        } else if self.check_reference_args(&name, &args, token.span) {
+            ASTNode::Call { name, args, span }
+        } else {
+            ASTNode::Error
+        }
+    }
+
+    // Arguments for `fn(N)` parameters have to be functions taking N
+    // arguments, and functions can't go where a plain value is expected.
+    fn check_reference_args(&mut self, name: &str, args: &[ASTNode], span: Span) -> bool {
+        let Some(func) = self.symbol_table.function(name) else {
+            return true;
+        };
+        for ((param, vtype), arg) in func.params.iter().zip(args) {
+            let expected: Option<usize> = reference_arity(vtype);
+            let given: Option<usize> = self.reference_arity_of(arg);
+            if expected == given {
+                continue;
+            }
+            let message: String = match (expected, given) {
+                (Some(arity), Some(other)) => format!(
+                    "Argument {} of {} takes a function of {} argument(s), got one of {}",
+                    param, name, arity, other
+                ),
+                (Some(arity), None) => format!(
+                    "Argument {} of {} takes a function of {} argument(s)",
+                    param, name, arity
+                ),
+                _ => format!("Argument {} of {} can't be a function", param, name),
             };
+            self.error(diagnostic::TYPE_MISMATCH, message, arg.span().unwrap_or(span));
+            return false;
         }
+        true
     }
 
-    fn parse_print(&mut self, segment: &Vec<Token>) -> ASTNode {
-        if segment.len() >= 2 {
-            return ASTNode::Print(Box::new(Self::parse_expression(
-                self,
-                &segment[1..].to_vec(),
-            )));
+    // Number of arguments of the function an expression refers to, if any.
+    fn reference_arity_of(&self, node: &ASTNode) -> Option<usize> {
+        match node {
+            ASTNode::FunctionRef { name, .. } => {
+                self.symbol_table.function(name).map(|func| func.params.len())
+            }
+            ASTNode::Variable { name, .. } => self
+                .symbol_table
+                .check_table(name.clone())
+                .and_then(|symbol| reference_arity(&symbol.vtype)),
+            _ => None,
         }
+    }
+}
 
-        ASTNode::Error
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::Tokenizer;
+
+    // Xorshift, enough to vary the expressions without pulling in a crate
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    // Expression text using every operator of the tables, parenthesized
+    // sub-expressions nest up to `depth` levels.
+    fn generate(rng: &mut Rng, depth: u32) -> String {
+        let mut out: String = String::new();
+        for i in 0..=rng.below(4) {
+            if i > 0 {
+                let (op, _, _) = BINARY_OPERATORS[rng.below(BINARY_OPERATORS.len())];
+                out += &format!(" {} ", op);
+            }
+            while rng.below(4) == 0 {
+                let (op, _) = UNARY_OPERATORS[rng.below(UNARY_OPERATORS.len())];
+                out += op;
+            }
+            if depth > 0 && rng.below(3) == 0 {
+                out += &format!("({})", generate(rng, depth - 1));
+            } else {
+                out += &rng.below(10).to_string();
+            }
+        }
+        out
+    }
+
+    fn apply(op: &str, a: i64, b: i64) -> Option<i64> {
+        match op {
+            "+" => Some(a.wrapping_add(b)),
+            "-" => Some(a.wrapping_sub(b)),
+            "*" => Some(a.wrapping_mul(b)),
+            "/" => a.checked_div(b),
+            "%" => a.checked_rem(b),
+            "^" => u32::try_from(b).ok().map(|exp| a.wrapping_pow(exp)),
+            "==" => Some((a == b) as i64),
+            "!=" => Some((a != b) as i64),
+            "<" => Some((a < b) as i64),
+            ">" => Some((a > b) as i64),
+            "<=" => Some((a <= b) as i64),
+            ">=" => Some((a >= b) as i64),
+            _ => None,
+        }
+    }
+
+    // Value of a parsed expression, None when it divides by zero or the like
+    fn evaluate(node: &ASTNode) -> Option<i64> {
+        match node {
+            ASTNode::Literal { value, .. } => value.parse().ok(),
+            ASTNode::UnaryOperation { op, operand, .. } if op == "-" => {
+                evaluate(operand).map(i64::wrapping_neg)
+            }
+            ASTNode::BinaryOperation { op, left, right, .. } => {
+                apply(op, evaluate(left)?, evaluate(right)?)
+            }
+            _ => panic!("unexpected node {:?}", node),
+        }
+    }
+
+    // Evaluates the text directly with one function per precedence level:
+    //
+    //   compare = sum (("==" | "!=" | "<" | ">" | "<=" | ">=") sum)*
+    //   sum     = product (("+" | "-") product)*
+    //   product = unary (("*" | "/" | "%") unary)*
+    //   unary   = "-" unary | power
+    //   power   = atom ("^" unary)?
+    //   atom    = number | "(" compare ")"
+    struct Reference {
+        tokens: Vec<String>,
+        pos: usize,
+    }
+
+    impl Reference {
+        fn eat(&mut self, ops: &[&str]) -> Option<String> {
+            let token: &String = self.tokens.get(self.pos)?;
+            if !ops.contains(&token.as_str()) {
+                return None;
+            }
+            self.pos += 1;
+            Some(token.clone())
+        }
+
+        fn compare(&mut self) -> Option<i64> {
+            let mut value: Option<i64> = self.sum();
+            while let Some(op) = self.eat(&["==", "!=", "<", ">", "<=", ">="]) {
+                let right: Option<i64> = self.sum();
+                value = value.zip(right).and_then(|(a, b)| apply(&op, a, b));
+            }
+            value
+        }
+
+        fn sum(&mut self) -> Option<i64> {
+            let mut value: Option<i64> = self.product();
+            while let Some(op) = self.eat(&["+", "-"]) {
+                let right: Option<i64> = self.product();
+                value = value.zip(right).and_then(|(a, b)| apply(&op, a, b));
+            }
+            value
+        }
+
+        fn product(&mut self) -> Option<i64> {
+            let mut value: Option<i64> = self.unary();
+            while let Some(op) = self.eat(&["*", "/", "%"]) {
+                let right: Option<i64> = self.unary();
+                value = value.zip(right).and_then(|(a, b)| apply(&op, a, b));
+            }
+            value
+        }
+
+        fn unary(&mut self) -> Option<i64> {
+            if self.eat(&["-"]).is_some() {
+                return self.unary().map(i64::wrapping_neg);
+            }
+            self.power()
+        }
+
+        fn power(&mut self) -> Option<i64> {
+            let base: Option<i64> = self.atom();
+            if self.eat(&["^"]).is_none() {
+                return base;
+            }
+            let exponent: Option<i64> = self.unary();
+            base.zip(exponent).and_then(|(a, b)| apply("^", a, b))
+        }
+
+        fn atom(&mut self) -> Option<i64> {
+            if self.eat(&["("]).is_some() {
+                let value: Option<i64> = self.compare();
+                assert!(self.eat(&[")"]).is_some(), "unbalanced reference input");
+                return value;
+            }
+            let value: Option<i64> = self.tokens[self.pos].parse().ok();
+            self.pos += 1;
+            value
+        }
+    }
+
+    fn tokenize(source: &str) -> Vec<Token> {
+        let mut tokenizer: Tokenizer = Tokenizer::new(source);
+        let mut tokens: Vec<Token> = Vec::new();
+        while !tokenizer.is_done() {
+            if let Some(token) = tokenizer.get_next_token() {
+                tokens.push(token);
+            }
+        }
+        tokens
+    }
+
+    // Parses `source` as a whole expression
+    fn parse(source: &str) -> ASTNode {
+        let tokens: Vec<Token> = tokenize(source);
+        let count: usize = tokens.len();
+        let mut symbol_table: SymbolTable = SymbolTable::new();
+        let mut parser: Parser = Parser::new(tokens, &mut symbol_table);
+        let node: ASTNode = parser.parse_expression();
+        assert!(parser.diagnostics().is_empty(), "{}: {:?}", source, parser.diagnostics());
+        assert_eq!(parser.pos, count, "{} was not parsed whole", source);
+        node
+    }
+
+    #[test]
+    fn operators_group_like_the_reference_evaluator() {
+        let mut rng: Rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..5000 {
+            let source: String = generate(&mut rng, 3);
+            let tokens: Vec<String> = tokenize(&source)
+                .into_iter()
+                .map(|token| token.value.unwrap_or_default())
+                .collect();
+            let mut reference: Reference = Reference { tokens, pos: 0 };
+            let expected: Option<i64> = reference.compare();
+
+            assert_eq!(evaluate(&parse(&source)), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn known_expressions() {
+        for (source, value) in [
+            ("10 - 3 - 2", 5),
+            ("100 / 10 / 5", 2),
+            ("7 % 4 + 1", 4),
+            ("1 + 7 % 4", 4),
+            ("2 ^ 3 ^ 2", 512),
+            ("-2 ^ 2", -4),
+            ("-2 * 3", -6),
+            ("2 * -3 + 1", -5),
+            ("- -4", 4),
+            ("-7 / 2", -3),
+            ("-7 % 2", -1),
+            ("7 / -2", -3),
+            ("-2147483648", -2147483648),
+            ("0xffff_ffff + 1", 0),
+            ("1 + 2 < 4", 1),
+            ("2 * 3 =< 5", 0),
+            ("3 > 2 == 1", 1),
+            ("2 =< 2", 1),
+            ("3 => 4", 0),
+            ("1 =! 1", 0),
+        ] {
+            assert_eq!(evaluate(&parse(source)), Some(value), "{}", source);
+        }
+    }
+    // Parses `source` as a program, returns its statements and the codes of
+    // the diagnostics reported
+    fn parse_program(source: &str) -> (Vec<Spanned>, Vec<&'static str>) {
+        let mut symbol_table: SymbolTable = SymbolTable::new();
+        let mut parser: Parser = Parser::new(tokenize(source), &mut symbol_table);
+        let program: Vec<Spanned> = parser.parse_program();
+        let codes: Vec<&str> = parser.diagnostics().iter().map(|diagnostic| diagnostic.code).collect();
+        (program, codes)
+    }
+
+    #[test]
+    fn recovery_keeps_the_next_declaration() {
+        let (program, codes) = parse_program("var x = 1 +\nvar y = 2;\nprint y;\n");
+        assert_eq!(codes, [diagnostic::UNEXPECTED_TOKEN]);
+        assert_eq!(program.len(), 2);
+    }
+
+    #[test]
+    fn functions_only_at_the_top_level() {
+        let source: &str = "var s = 0;\nfor i = 0 to 3;\n    func h;\n        s = s + i;\n    endfunc;\nendfor;\n";
+        let (_, codes) = parse_program(source);
+        assert_eq!(codes, [diagnostic::MISPLACED_STATEMENT]);
+    }
+    #[test]
+    fn loop_jumps_stay_inside_the_function() {
+        let source: &str = "var x = 1;\nwhile x < 3;\n    func f;\n        break;\n    endfunc;\nendwhile;\n";
+        let (_, codes) = parse_program(source);
+        assert_eq!(codes, [diagnostic::MISPLACED_STATEMENT, diagnostic::MISPLACED_STATEMENT]);
+    }
+    #[test]
+    fn named_arguments_become_a_plain_call() {
+        let source: &str = "func f: a, b;\n    print a - b;\nendfunc;\nvar x = 1;\ncall f: b = 2, a = x;\n";
+        let (program, codes) = parse_program(source);
+        assert!(codes.is_empty(), "{:?}", codes);
+        let Some(ASTNode::Call { name, args, .. }) = program.last().map(|statement| &statement.node) else {
+            panic!("{:?}", program.last());
+        };
+        assert_eq!(name, "f");
+        assert!(
+            matches!(&args[..], [ASTNode::Variable { name, .. }, ASTNode::Literal { value, .. }] if name == "x" && value == "2"),
+            "{:?}",
+            args
+        );
+
+        let (_, codes) = parse_program("func f: a;\nendfunc;\ncall f: a = zz;\n");
+        assert_eq!(codes, [diagnostic::UNDEFINED_VARIABLE]);
+    }
+    #[test]
+    fn decimal_literals_are_signed() {
+        let (_, codes) = parse_program("var x = 2147483648;\n");
+        assert_eq!(codes, [diagnostic::INVALID_LITERAL]);
+        let (_, codes) = parse_program("var x = -2147483648;\nvar y = 2147483647;\n");
+        assert!(codes.is_empty(), "{:?}", codes);
+    }
+    #[test]
+    fn own_hex_function_is_called() {
+        let source: &str = "func hex: x;\n    return x + 1;\nendfunc;\nprint hex(1);\n";
+        let (program, codes) = parse_program(source);
+        assert!(codes.is_empty(), "{:?}", codes);
+        let Some(ASTNode::Print { args, .. }) = program.last().map(|statement| &statement.node) else {
+            panic!("{:?}", program.last());
+        };
+        assert_eq!(args[0].0, 10);
+        assert!(matches!(*args[0].1, ASTNode::Call { .. }), "{:?}", args[0].1);
     }
 }
@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use crate::ast::Spanned;
+use crate::diagnostic::Diagnostic;
+use crate::parser::Parser;
+use crate::symbol_table::SymbolTable;
+use crate::token::{Token, TokenType};
+use crate::tokenizer::Tokenizer;
+
+// Library of `.idk` functions compiled along with every program. Only the
+// functions a program ends up calling are emitted, see `AST::link`.
+pub const SOURCE: &str = include_str!("prelude.idk");
+
+// Names of the functions defined in a token stream.
+pub fn defined_functions(tokens: &[Token]) -> HashSet<String> {
+    tokens
+        .windows(2)
+        .filter(|pair| pair[0].ttype == TokenType::Func && pair[1].ttype == TokenType::Variable)
+        .filter_map(|pair| pair[1].value.clone())
+        .collect()
+}
+
+// Names mentioned anywhere in a token stream, calls and function references
+// among them.
+fn mentioned_names(tokens: &[Token]) -> HashSet<String> {
+    tokens
+        .iter()
+        .filter(|token| token.ttype == TokenType::Variable)
+        .filter_map(|token| token.value.clone())
+        .collect()
+}
+
+// Each prelude function with the range of its tokens, from `func` to the `;`
+// after `endfunc`.
+fn functions(all: &[Token]) -> Vec<(String, Range<usize>)> {
+    let mut functions: Vec<(String, Range<usize>)> = Vec::new();
+    let mut start: Option<usize> = None;
+    for (i, token) in all.iter().enumerate() {
+        if token.ttype == TokenType::Func {
+            start = Some(i);
+        }
+        if token.ttype == TokenType::SemiColon && i > 0 && all[i - 1].ttype == TokenType::Endfunc {
+            if let Some(start) = start.take() {
+                let name: String = all[start + 1].value.clone().unwrap_or_default();
+                functions.push((name, start..i + 1));
+            }
+        }
+    }
+    functions
+}
+
+// Tokens of the prelude functions `program` may use, directly or through
+// other prelude functions. Functions the program defines itself are left out
+// so that programs can bring their own version, and so are the ones it never
+// mentions, a replaced `swap` with other parameters doesn't break `sort`
+// unless `sort` is used.
+pub fn tokens(program: &[Token]) -> Vec<Token> {
+    let mut tokenizer: Tokenizer = Tokenizer::new(SOURCE);
+    let mut all: Vec<Token> = Vec::new();
+    while !tokenizer.is_done() {
+        if let Some(token) = tokenizer.get_next_token() {
+            all.push(token);
+        }
+    }
+
+    let functions: Vec<(String, Range<usize>)> = functions(&all);
+    let overridden: HashSet<String> = defined_functions(program);
+    let mut included: HashSet<String> = HashSet::new();
+    let mut pending: Vec<String> = mentioned_names(program).into_iter().collect();
+    while let Some(name) = pending.pop() {
+        if overridden.contains(&name) || included.contains(&name) {
+            continue;
+        }
+        if let Some((_, range)) = functions.iter().find(|(func, _)| *func == name) {
+            pending.extend(mentioned_names(&all[range.clone()]));
+            included.insert(name);
+        }
+    }
+
+    functions
+        .iter()
+        .filter(|(name, _)| included.contains(name))
+        .flat_map(|(_, range)| all[range.clone()].iter().cloned())
+        .collect()
+}
+
+// Parses the prelude functions `program` needs. Its own signatures are
+// registered first, prelude functions may call the ones it replaces.
+pub fn parse(program: &[Token], symbol_table: &mut SymbolTable) -> (Vec<Spanned>, Vec<Diagnostic>) {
+    Parser::register_functions(program, symbol_table);
+    let mut parser: Parser = Parser::new(tokens(program), symbol_table);
+    let library: Vec<Spanned> = parser.parse_program();
+    (library, parser.diagnostics().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::ASTNode;
+
+    fn tokenize(source: &str) -> Vec<Token> {
+        let mut tokenizer: Tokenizer = Tokenizer::new(source);
+        let mut tokens: Vec<Token> = Vec::new();
+        while !tokenizer.is_done() {
+            if let Some(token) = tokenizer.get_next_token() {
+                tokens.push(token);
+            }
+        }
+        tokens
+    }
+
+    // Names of the prelude functions parsed for `source`, and whether they
+    // parsed cleanly
+    fn library(source: &str) -> (HashSet<String>, bool) {
+        let mut symbol_table: SymbolTable = SymbolTable::new();
+        let (library, diagnostics) = parse(&tokenize(source), &mut symbol_table);
+        let names: HashSet<String> = library
+            .iter()
+            .filter_map(|statement| match &statement.node {
+                ASTNode::FunctionDef { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        (names, diagnostics.is_empty())
+    }
+
+    #[test]
+    fn own_swap_with_other_parameters() {
+        let (functions, clean) = library("func swap: x, y;\n    print x;\nendfunc;\nswap(1, 2);\n");
+        assert!(clean);
+        assert!(functions.is_empty(), "{:?}", functions);
+    }
+
+    #[test]
+    fn pulls_in_what_used_functions_call() {
+        let (functions, clean) = library("var a[4];\nsort(a, 4);\n");
+        assert!(clean);
+        assert_eq!(functions, HashSet::from(["sort".to_string(), "swap".to_string()]));
+    }
+
+    #[test]
+    fn own_swap_used_by_sort() {
+        let source: &str = "func swap: arr[], i, j;\n    print i;\nendfunc;\nvar a[4];\nsort(a, 4);\n";
+        let (functions, clean) = library(source);
+        assert!(clean);
+        assert_eq!(functions, HashSet::from(["sort".to_string()]));
+    }
+}
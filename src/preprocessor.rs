@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+
+use crate::diagnostic::{self, Diagnostic};
+use crate::token::Span;
+
+// Textual preprocessing done before tokenizing. Directive lines and the lines
+// of inactive `#ifdef` branches are blanked instead of removed and macro
+// bodies are single line, so line N of the output is line N of the source and
+// token line numbers keep pointing at the real code.
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Option<Vec<String>>,
+    body: String,
+}
+
+// Nested expansions deeper than this are treated as a runaway macro
+const MAX_DEPTH: usize = 64;
+
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+}
+
+impl Preprocessor {
+    // `defines` are the `-D NAME=VAL` options, they behave like `#define`
+    // lines at the top of the file.
+    pub fn new(defines: &[(String, String)]) -> Self {
+        let mut macros: HashMap<String, Macro> = HashMap::new();
+        for (name, value) in defines {
+            macros.insert(
+                name.clone(),
+                Macro {
+                    params: None,
+                    body: value.clone(),
+                },
+            );
+        }
+
+        Preprocessor { macros }
+    }
+
+    pub fn process(&mut self, source: &str) -> Result<String, Box<Diagnostic>> {
+        let mut output: Vec<String> = Vec::new();
+        // One entry per open `#ifdef`: (was its condition true, seen `#else`)
+        let mut conditions: Vec<(bool, bool)> = Vec::new();
+        let mut open_lines: Vec<usize> = Vec::new();
+
+        let lines: Vec<&str> = source.lines().collect();
+
+        for (i, line) in lines.iter().enumerate() {
+            let line_number: usize = i + 1;
+            let active: bool = conditions
+                .iter()
+                .all(|(taken, in_else)| taken != in_else);
+            let trimmed: &str = line.trim_start();
+
+            let Some(directive) = trimmed.strip_prefix('#') else {
+                if active {
+                    output.push(self.expand(line, 0).map_err(|err| at(source, line_number, err))?);
+                } else {
+                    output.push(String::new());
+                }
+                continue;
+            };
+            output.push(String::new());
+
+            let (keyword, rest) = split_word(directive);
+            match keyword {
+                "ifdef" => {
+                    let (name, _) = split_word(rest);
+                    if name.is_empty() {
+                        return Err(at(source, line_number, "#ifdef needs a macro name".into()));
+                    }
+                    conditions.push((self.macros.contains_key(name), false));
+                    open_lines.push(line_number);
+                }
+                "else" => match conditions.last_mut() {
+                    Some((_, in_else)) if !*in_else => *in_else = true,
+                    Some(_) => return Err(at(source, line_number, "#else after #else".into())),
+                    None => return Err(at(source, line_number, "#else without #ifdef".into())),
+                },
+                "endif" => {
+                    open_lines.pop();
+                    if conditions.pop().is_none() {
+                        return Err(at(source, line_number, "#endif without #ifdef".into()));
+                    }
+                }
+                "define" if active => {
+                    let (name, definition) = parse_define(rest).map_err(|err| at(source, line_number, err))?;
+                    self.macros.insert(name, definition);
+                }
+                "define" => {}
+                _ if !active => {}
+                _ => {
+                    return Err(at(
+                        source,
+                        line_number,
+                        format!("Unknown preprocessor directive #{}", keyword),
+                    ))
+                }
+            }
+        }
+
+        if let Some(line_number) = open_lines.last() {
+            return Err(at(source, *line_number, "#ifdef without a matching #endif".into()));
+        }
+
+        Ok(output.join("\n") + "\n")
+    }
+
+    // Replaces every macro name in `text`, string literals are left alone.
+    fn expand(&self, text: &str, depth: usize) -> Result<String, String> {
+        if depth > MAX_DEPTH {
+            return Err("Macro expansion too deep, is a macro recursive?".into());
+        }
+
+        let chars: Vec<char> = text.chars().collect();
+        let mut result: String = String::new();
+        let mut i: usize = 0;
+
+        while i < chars.len() {
+            let ch: char = chars[i];
+            if ch == '"' {
+                let start: usize = i;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i = (i + 1).min(chars.len());
+                result.extend(&chars[start..i]);
+                continue;
+            }
+            if !is_ident_start(ch) {
+                result.push(ch);
+                i += 1;
+                continue;
+            }
+
+            let start: usize = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+
+            let Some(definition) = self.macros.get(&word) else {
+                result.push_str(&word);
+                continue;
+            };
+            let Some(params) = &definition.params else {
+                result.push_str(&self.expand(&definition.body, depth + 1)?);
+                continue;
+            };
+
+            // A function-like macro name without arguments is left as is
+            let mut open: usize = i;
+            while open < chars.len() && chars[open].is_whitespace() {
+                open += 1;
+            }
+            if open == chars.len() || chars[open] != '(' {
+                result.push_str(&word);
+                continue;
+            }
+
+            let (args, end) = split_arguments(&chars, open)
+                .ok_or_else(|| format!("Unterminated arguments of macro {}", word))?;
+            if args.len() != params.len() {
+                return Err(format!(
+                    "Macro {} takes {} argument(s), got {}",
+                    word,
+                    params.len(),
+                    args.len()
+                ));
+            }
+
+            let mut bound: HashMap<&str, String> = HashMap::new();
+            for (param, arg) in params.iter().zip(args) {
+                bound.insert(param.as_str(), self.expand(arg.trim(), depth + 1)?);
+            }
+            let body: String = substitute(&definition.body, &bound);
+            result.push_str(&self.expand(&body, depth + 1)?);
+            i = end;
+        }
+
+        Ok(result)
+    }
+}
+
+// Preprocessor errors underline the whole directive or source line
+fn at(source: &str, line: usize, message: String) -> Box<Diagnostic> {
+    Box::new(Diagnostic::error(diagnostic::PREPROCESSOR, message, line_span(source, line)))
+}
+
+// Moves a span of the processed text back onto `source`. Lines the
+// preprocessor left alone keep their columns, a line changed by a macro is
+// underlined whole since the expansion has no counterpart in the source.
+pub fn original_span(source: &str, processed: &str, span: Span) -> Span {
+    let original_line: Option<&str> = span.line.checked_sub(1).and_then(|i| source.lines().nth(i));
+    let processed_line: Option<&str> = span.line.checked_sub(1).and_then(|i| processed.lines().nth(i));
+    if original_line.is_none() || original_line != processed_line {
+        return line_span(source, span.line);
+    }
+
+    let from: usize = line_start(processed, span.line);
+    let to: usize = line_start(source, span.line);
+    Span {
+        start: span.start - from + to,
+        end: span.end - from + to,
+        ..span
+    }
+}
+
+// The text of a line without its indentation
+fn line_span(source: &str, line: usize) -> Span {
+    let start: usize = line_start(source, line);
+    let text: &str = source[start..].lines().next().unwrap_or("");
+    let indent: usize = text.len() - text.trim_start().len();
+    Span {
+        line,
+        column: indent + 1,
+        start: start + indent,
+        end: start + indent + text.trim().len(),
+    }
+}
+
+// Byte offset of the start of line `line` (from 1) in `source`
+fn line_start(source: &str, line: usize) -> usize {
+    if line <= 1 {
+        return 0;
+    }
+    source
+        .match_indices('\n')
+        .nth(line - 2)
+        .map_or(source.len(), |(newline, _)| newline + 1)
+}
+
+fn is_ident_start(ch: char) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_'
+}
+
+fn is_ident_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text: &str = text.trim_start();
+    let end: usize = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim())
+}
+
+// `NAME value` or `NAME(a, b) value`, the parameter list has to follow the
+// name directly.
+fn parse_define(rest: &str) -> Result<(String, Macro), String> {
+    let name_end: usize = rest
+        .find(|ch: char| !is_ident_char(ch))
+        .unwrap_or(rest.len());
+    let name: &str = &rest[..name_end];
+    if name.is_empty() || !name.starts_with(is_ident_start) {
+        return Err("#define needs a macro name".into());
+    }
+
+    let after: &str = &rest[name_end..];
+    let Some(list) = after.strip_prefix('(') else {
+        let definition: Macro = Macro {
+            params: None,
+            body: after.trim().to_string(),
+        };
+        return Ok((name.to_string(), definition));
+    };
+
+    let close: usize = list
+        .find(')')
+        .ok_or_else(|| format!("Unterminated parameter list of macro {}", name))?;
+    let mut params: Vec<String> = Vec::new();
+    if !list[..close].trim().is_empty() {
+        for param in list[..close].split(',') {
+            let param: &str = param.trim();
+            if param.is_empty() || !param.starts_with(is_ident_start) || !param.chars().all(is_ident_char) {
+                return Err(format!("Bad parameter '{}' of macro {}", param, name));
+            }
+            params.push(param.to_string());
+        }
+    }
+
+    let definition: Macro = Macro {
+        params: Some(params),
+        body: list[close + 1..].trim().to_string(),
+    };
+    Ok((name.to_string(), definition))
+}
+
+// Arguments of a macro call starting at the `(` in `chars[open]`. Returns the
+// arguments and the index right after the closing `)`.
+fn split_arguments(chars: &[char], open: usize) -> Option<(Vec<String>, usize)> {
+    let mut args: Vec<String> = Vec::new();
+    let mut current: String = String::new();
+    let mut depth: usize = 0;
+
+    for (i, ch) in chars.iter().enumerate().skip(open) {
+        match ch {
+            '(' => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            }
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    if !(args.is_empty() && current.trim().is_empty()) {
+                        args.push(current);
+                    }
+                    return Some((args, i + 1));
+                }
+            }
+            ',' if depth == 1 => {
+                args.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(*ch);
+    }
+
+    None
+}
+
+// Replaces whole-word occurrences of the parameters in a macro body.
+fn substitute(body: &str, bound: &HashMap<&str, String>) -> String {
+    let mut result: String = String::new();
+    let mut word: String = String::new();
+
+    for ch in body.chars().chain(std::iter::once(' ')) {
+        if is_ident_char(ch) {
+            word.push(ch);
+            continue;
+        }
+        match bound.get(word.as_str()) {
+            Some(value) => result.push_str(value),
+            None => result.push_str(&word),
+        }
+        word.clear();
+        result.push(ch);
+    }
+    result.pop();
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(source: &str) -> Result<String, Box<Diagnostic>> {
+        Preprocessor::new(&[]).process(source)
+    }
+
+    // Message of the error `source` fails with and the line it points at
+    fn error(source: &str) -> (String, usize) {
+        let err: Box<Diagnostic> = process(source).expect_err(source);
+        (err.message, err.span.line)
+    }
+
+    #[test]
+    fn parameterized_macros() {
+        let output: String = process("#define SQ(x) ((x) * (x))\nvar a = SQ(2 + 1);\n").unwrap();
+        assert_eq!(output, "\nvar a = ((2 + 1) * (2 + 1));\n");
+
+        let source: &str = "#define ADD(a, b) a + b\n#define TWICE(x) ADD(x, x)\nprint TWICE(3);\n";
+        let output: String = process(source).unwrap();
+        assert_eq!(output, "\n\nprint 3 + 3;\n");
+    }
+
+    #[test]
+    fn nested_parentheses_in_arguments() {
+        let source: &str = "#define MAX(a, b) max(a, b)\nprint MAX(f(1, 2), (3 + 4));\n";
+        assert_eq!(process(source).unwrap(), "\nprint max(f(1, 2), (3 + 4));\n");
+    }
+
+    #[test]
+    fn strings_and_other_words_are_left_alone() {
+        let source: &str = "#define N 5\nprint \"N\", N, NN;\n";
+        assert_eq!(process(source).unwrap(), "\nprint \"N\", 5, NN;\n");
+    }
+
+    #[test]
+    fn conditional_errors() {
+        assert_eq!(
+            error("#ifdef X\n#else\n#else\n#endif\n"),
+            ("#else after #else".to_string(), 3)
+        );
+        assert_eq!(
+            error("print 1;\n#ifdef X\nprint 2;\n"),
+            ("#ifdef without a matching #endif".to_string(), 2)
+        );
+        assert_eq!(error("#endif\n"), ("#endif without #ifdef".to_string(), 1));
+        let (message, line) = error("#define F(x) F(x)\nprint F(1);\n");
+        assert!(message.contains("recursive"), "{}", message);
+        assert_eq!(line, 2);
+    }
+
+    #[test]
+    fn command_line_defines() {
+        let defines: Vec<(String, String)> = vec![("DEBUG".to_string(), "4".to_string())];
+        let source: &str = "#ifdef DEBUG\nprint DEBUG;\n#else\nprint 0;\n#endif\n";
+        let output: String = Preprocessor::new(&defines).process(source).unwrap();
+        assert_eq!(output, "\nprint 4;\n\n\n\n");
+
+        // They act like `#define` lines at the top, the file can redefine them
+        let source: &str = "#define DEBUG 1\nprint DEBUG;\n";
+        let output: String = Preprocessor::new(&defines).process(source).unwrap();
+        assert_eq!(output, "\nprint 1;\n");
+    }
+
+    #[test]
+    fn lines_are_preserved() {
+        let source: &str = "#define N 3\n#ifdef M\nprint 1;\n#else\nprint N;\n#endif\nprint 2;\n";
+        let output: String = process(source).unwrap();
+        assert_eq!(output.lines().count(), source.lines().count());
+        assert_eq!(output.lines().nth(4), Some("print 3;"));
+        assert_eq!(output.lines().nth(6), Some("print 2;"));
+    }
+
+    #[test]
+    fn spans_map_back_to_the_source() {
+        let source: &str = "#define N 10\nvar x = N;\nprint y;\n";
+        let processed: String = process(source).unwrap();
+        let start: usize = processed.find('y').unwrap();
+        let span: Span = Span {
+            line: 3,
+            column: 7,
+            start,
+            end: start + 1,
+        };
+        let mapped: Span = original_span(source, &processed, span);
+        assert_eq!(&source[mapped.start..mapped.end], "y");
+
+        // A line changed by a macro is pointed at whole
+        let start: usize = processed.find("10").unwrap();
+        let span: Span = Span {
+            line: 2,
+            column: 9,
+            start,
+            end: start + 2,
+        };
+        let mapped: Span = original_span(source, &processed, span);
+        assert_eq!(&source[mapped.start..mapped.end], "var x = N;");
+    }
+}
@@ -0,0 +1,414 @@
+// Assembly routines that generated programs call into. The assembler only
+// emits the ones a program actually uses, see `Assembler::require`.
+
+// eax = value, ebx = base (2..=16), ecx = file descriptor.
+// Writes the digits of the value, signed in base 10 and unsigned in the
+// others. Keeps edi, which may hold the variable of a `for` loop.
+pub fn print_num() -> String {
+    let mut func: String = String::new();
+    func.push_str("print_num:\n");
+    func.push_str("push esi\n");
+    func.push_str("push edi\n");
+    func.push_str("push ecx\n");
+    func.push_str("push edx\n");
+    func.push_str("xor esi, esi\n");
+    func.push_str("cmp ebx, 10\n");
+    func.push_str("jne .digits\n");
+    func.push_str("test eax, eax\n");
+    func.push_str("jns .digits\n");
+    func.push_str("neg eax\n");
+    func.push_str("mov esi, 1\n");
+    func.push_str(".digits:\n");
+    func.push_str("mov edi, buffer + 32\n");
+    func.push_str("mov byte [edi], 0\n");
+    func.push_str(".convert_loop:\n");
+    func.push_str("dec edi\n");
+    func.push_str("xor edx, edx\n");
+    func.push_str("div ebx\n");
+    func.push_str("mov dl, [digits + edx]\n");
+    func.push_str("mov [edi], dl\n");
+    func.push_str("test eax, eax\n");
+    func.push_str("jnz .convert_loop\n");
+    func.push_str("test esi, esi\n");
+    func.push_str("jz .write\n");
+    func.push_str("dec edi\n");
+    func.push_str("mov byte [edi], '-'\n");
+    func.push_str(".write:\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, [esp + 4]\n");
+    func.push_str("mov ecx, edi\n");
+    func.push_str("mov edx, buffer + 32\n");
+    func.push_str("sub edx, edi\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop edx\n");
+    func.push_str("pop ecx\n");
+    func.push_str("pop edi\n");
+    func.push_str("pop esi\n");
+    func.push_str("ret\n");
+    func
+}
+
+// al = character, ecx = file descriptor.
+pub fn print_char() -> String {
+    let mut func: String = String::new();
+    func.push_str("print_char:\n");
+    func.push_str("push ecx\n");
+    func.push_str("push edx\n");
+    func.push_str("push eax\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, [esp + 8]\n");
+    func.push_str("mov ecx, esp\n");
+    func.push_str("mov edx, 1\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop eax\n");
+    func.push_str("pop edx\n");
+    func.push_str("pop ecx\n");
+    func.push_str("ret\n");
+    func
+}
+
+// eax = line of the failed assertion. Reports it on stderr and exits with 1.
+pub fn assert_fail() -> String {
+    let mut func: String = String::new();
+    func.push_str("assert_fail:\n");
+    func.push_str("push eax\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, 2\n");
+    func.push_str("mov ecx, assert_msg\n");
+    func.push_str("mov edx, assert_msg_len\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop eax\n");
+    func.push_str("mov ebx, 10\n");
+    func.push_str("mov ecx, 2\n");
+    func.push_str("call print_num\n");
+    func.push_str("mov eax, 0xA\n");
+    func.push_str("mov ecx, 2\n");
+    func.push_str("call print_char\n");
+    func.push_str("mov eax, 1\n");
+    func.push_str("mov ebx, 1\n");
+    func.push_str("int 0x80\n");
+    func
+}
+
+// eax = index, ebx = line, ecx = array name, edx = array name length.
+// Reports the out of range access on stderr and exits with 1.
+pub fn bounds_fail() -> String {
+    let mut func: String = String::new();
+    func.push_str("bounds_fail:\n");
+    func.push_str("push ebx\n");
+    func.push_str("push edx\n");
+    func.push_str("push ecx\n");
+    func.push_str("push eax\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, 2\n");
+    func.push_str("mov ecx, bounds_msg_index\n");
+    func.push_str("mov edx, bounds_msg_index_len\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop eax\n");
+    func.push_str("mov ebx, 10\n");
+    func.push_str("mov ecx, 2\n");
+    func.push_str("call print_num\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, 2\n");
+    func.push_str("mov ecx, bounds_msg_array\n");
+    func.push_str("mov edx, bounds_msg_array_len\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop ecx\n");
+    func.push_str("pop edx\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, 2\n");
+    func.push_str("int 0x80\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, 2\n");
+    func.push_str("mov ecx, bounds_msg_at\n");
+    func.push_str("mov edx, bounds_msg_at_len\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop eax\n");
+    func.push_str("mov ebx, 10\n");
+    func.push_str("mov ecx, 2\n");
+    func.push_str("call print_num\n");
+    func.push_str("mov eax, 0xA\n");
+    func.push_str("mov ecx, 2\n");
+    func.push_str("call print_char\n");
+    func.push_str("mov eax, 1\n");
+    func.push_str("mov ebx, 1\n");
+    func.push_str("int 0x80\n");
+    func
+}
+
+// eax = line, ecx = message, edx = message length.
+// Reports the failed arithmetic operation on stderr and exits with 1.
+pub fn arith_fail() -> String {
+    let mut func: String = String::new();
+    func.push_str("arith_fail:\n");
+    func.push_str("push eax\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, 2\n");
+    func.push_str("int 0x80\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, 2\n");
+    func.push_str("mov ecx, arith_msg_at\n");
+    func.push_str("mov edx, arith_msg_at_len\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop eax\n");
+    func.push_str("mov ebx, 10\n");
+    func.push_str("mov ecx, 2\n");
+    func.push_str("call print_num\n");
+    func.push_str("mov eax, 0xA\n");
+    func.push_str("mov ecx, 2\n");
+    func.push_str("call print_char\n");
+    func.push_str("mov eax, 1\n");
+    func.push_str("mov ebx, 1\n");
+    func.push_str("int 0x80\n");
+    func
+}
+
+// eax = number of dwords. Returns a pointer to the block in eax, or 0 when
+// the heap can't grow. Blocks carry an 8 byte header (size, next free) and
+// freed ones are reused first fit before the break is moved with `brk`.
+pub fn alloc() -> String {
+    let mut func: String = String::new();
+    func.push_str("alloc:\n");
+    func.push_str("push ebx\n");
+    func.push_str("push ecx\n");
+    func.push_str("push edx\n");
+    func.push_str("lea ecx, [eax*4 + 8]\n");
+    func.push_str("mov edx, heap_free\n");
+    func.push_str(".search:\n");
+    func.push_str("mov ebx, [edx]\n");
+    func.push_str("test ebx, ebx\n");
+    func.push_str("jz .grow\n");
+    func.push_str("cmp [ebx], ecx\n");
+    func.push_str("jae .take\n");
+    func.push_str("lea edx, [ebx + 4]\n");
+    func.push_str("jmp .search\n");
+    func.push_str(".take:\n");
+    func.push_str("mov eax, [ebx + 4]\n");
+    func.push_str("mov [edx], eax\n");
+    func.push_str("lea eax, [ebx + 8]\n");
+    func.push_str("jmp .done\n");
+    func.push_str(".grow:\n");
+    func.push_str("mov eax, [heap_end]\n");
+    func.push_str("test eax, eax\n");
+    func.push_str("jnz .extend\n");
+    func.push_str("mov eax, 45\n");
+    func.push_str("xor ebx, ebx\n");
+    func.push_str("int 0x80\n");
+    func.push_str("mov [heap_end], eax\n");
+    func.push_str(".extend:\n");
+    func.push_str("mov edx, eax\n");
+    func.push_str("lea ebx, [eax + ecx]\n");
+    func.push_str("mov eax, 45\n");
+    func.push_str("int 0x80\n");
+    func.push_str("cmp eax, ebx\n");
+    func.push_str("jb .fail\n");
+    func.push_str("mov [heap_end], eax\n");
+    func.push_str("mov [edx], ecx\n");
+    func.push_str("lea eax, [edx + 8]\n");
+    func.push_str("jmp .done\n");
+    func.push_str(".fail:\n");
+    func.push_str("xor eax, eax\n");
+    func.push_str(".done:\n");
+    func.push_str("pop edx\n");
+    func.push_str("pop ecx\n");
+    func.push_str("pop ebx\n");
+    func.push_str("ret\n");
+    func
+}
+
+// eax = pointer returned by `alloc`. Puts the block on the free list.
+pub fn free() -> String {
+    let mut func: String = String::new();
+    func.push_str("free:\n");
+    func.push_str("test eax, eax\n");
+    func.push_str("jz .done\n");
+    func.push_str("push ebx\n");
+    func.push_str("sub eax, 8\n");
+    func.push_str("mov ebx, [heap_free]\n");
+    func.push_str("mov [eax + 4], ebx\n");
+    func.push_str("mov [heap_free], eax\n");
+    func.push_str("pop ebx\n");
+    func.push_str("xor eax, eax\n");
+    func.push_str(".done:\n");
+    func.push_str("ret\n");
+    func
+}
+
+// Returns the number of command line arguments, program name included.
+pub fn argc() -> String {
+    let mut func: String = String::new();
+    func.push_str("argc:\n");
+    func.push_str("mov eax, [start_esp]\n");
+    func.push_str("mov eax, [eax]\n");
+    func.push_str("ret\n");
+    func
+}
+
+// eax = argument index. Returns the argument parsed as a decimal integer,
+// or 0 when there is no such argument.
+pub fn arg_int() -> String {
+    let mut func: String = String::new();
+    func.push_str("arg_int:\n");
+    func.push_str("push ebx\n");
+    func.push_str("push ecx\n");
+    func.push_str("push esi\n");
+    func.push_str("mov esi, [start_esp]\n");
+    func.push_str("cmp eax, [esi]\n");
+    func.push_str("jae .missing\n");
+    func.push_str("mov esi, [esi + 4 + eax*4]\n");
+    func.push_str("xor eax, eax\n");
+    func.push_str("xor ecx, ecx\n");
+    func.push_str("cmp byte [esi], '-'\n");
+    func.push_str("jne .digits\n");
+    func.push_str("inc ecx\n");
+    func.push_str("inc esi\n");
+    func.push_str(".digits:\n");
+    func.push_str("movzx ebx, byte [esi]\n");
+    func.push_str("sub ebx, '0'\n");
+    func.push_str("cmp ebx, 9\n");
+    func.push_str("ja .end\n");
+    func.push_str("imul eax, eax, 10\n");
+    func.push_str("add eax, ebx\n");
+    func.push_str("inc esi\n");
+    func.push_str("jmp .digits\n");
+    func.push_str(".end:\n");
+    func.push_str("test ecx, ecx\n");
+    func.push_str("jz .done\n");
+    func.push_str("neg eax\n");
+    func.push_str("jmp .done\n");
+    func.push_str(".missing:\n");
+    func.push_str("xor eax, eax\n");
+    func.push_str(".done:\n");
+    func.push_str("pop esi\n");
+    func.push_str("pop ecx\n");
+    func.push_str("pop ebx\n");
+    func.push_str("ret\n");
+    func
+}
+
+// eax = path, ebx = flags. Opens the file with mode 0644 for created files
+// and returns the descriptor, or a negative error code.
+pub fn open() -> String {
+    let mut func: String = String::new();
+    func.push_str("open:\n");
+    func.push_str("push ebx\n");
+    func.push_str("push ecx\n");
+    func.push_str("push edx\n");
+    func.push_str("mov ecx, ebx\n");
+    func.push_str("mov ebx, eax\n");
+    func.push_str("mov edx, 420\n");
+    func.push_str("mov eax, 5\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop edx\n");
+    func.push_str("pop ecx\n");
+    func.push_str("pop ebx\n");
+    func.push_str("ret\n");
+    func
+}
+
+// eax = descriptor, ebx = buffer, ecx = count. Buffers hold one byte per
+// element, so the bytes read are widened in place from the back. Returns the
+// number of bytes read, or a negative error code.
+pub fn read_bytes() -> String {
+    let mut func: String = String::new();
+    func.push_str("read_bytes:\n");
+    func.push_str("push ebx\n");
+    func.push_str("push ecx\n");
+    func.push_str("push edx\n");
+    func.push_str("push esi\n");
+    func.push_str("mov esi, ebx\n");
+    func.push_str("mov edx, ecx\n");
+    func.push_str("mov ebx, eax\n");
+    func.push_str("mov ecx, esi\n");
+    func.push_str("mov eax, 3\n");
+    func.push_str("int 0x80\n");
+    func.push_str("test eax, eax\n");
+    func.push_str("jle .done\n");
+    func.push_str("mov ecx, eax\n");
+    func.push_str(".widen:\n");
+    func.push_str("dec ecx\n");
+    func.push_str("movzx edx, byte [esi + ecx]\n");
+    func.push_str("mov [esi + ecx*4], edx\n");
+    func.push_str("test ecx, ecx\n");
+    func.push_str("jnz .widen\n");
+    func.push_str(".done:\n");
+    func.push_str("pop esi\n");
+    func.push_str("pop edx\n");
+    func.push_str("pop ecx\n");
+    func.push_str("pop ebx\n");
+    func.push_str("ret\n");
+    func
+}
+
+// eax = descriptor, ebx = buffer, ecx = count. The low byte of each element
+// is copied to the stack, 256 at a time, and written from there so the buffer
+// itself is never touched. Returns the number of bytes written, or a negative
+// error code. Keeps edi, which may hold the variable of a `for` loop.
+pub fn write_bytes() -> String {
+    let mut func: String = String::new();
+    func.push_str("write_bytes:\n");
+    func.push_str("push ebx\n");
+    func.push_str("push ecx\n");
+    func.push_str("push edx\n");
+    func.push_str("push esi\n");
+    func.push_str("push edi\n");
+    func.push_str("push ebp\n");
+    func.push_str("mov ebp, eax\n");
+    func.push_str("mov esi, ebx\n");
+    func.push_str("mov edi, ecx\n");
+    func.push_str("push dword 0\n");
+    func.push_str("sub esp, 256\n");
+    func.push_str(".chunk:\n");
+    func.push_str("test edi, edi\n");
+    func.push_str("jz .done\n");
+    func.push_str("mov edx, edi\n");
+    func.push_str("cmp edx, 256\n");
+    func.push_str("jbe .narrow_start\n");
+    func.push_str("mov edx, 256\n");
+    func.push_str(".narrow_start:\n");
+    func.push_str("xor ecx, ecx\n");
+    func.push_str(".narrow:\n");
+    func.push_str("mov al, [esi + ecx*4]\n");
+    func.push_str("mov [esp + ecx], al\n");
+    func.push_str("inc ecx\n");
+    func.push_str("cmp ecx, edx\n");
+    func.push_str("jb .narrow\n");
+    func.push_str("mov eax, 4\n");
+    func.push_str("mov ebx, ebp\n");
+    func.push_str("mov ecx, esp\n");
+    func.push_str("int 0x80\n");
+    func.push_str("test eax, eax\n");
+    func.push_str("jl .failed\n");
+    func.push_str("add [esp + 256], eax\n");
+    func.push_str("cmp eax, edx\n");
+    func.push_str("jne .done\n");
+    func.push_str("lea esi, [esi + edx*4]\n");
+    func.push_str("sub edi, edx\n");
+    func.push_str("jmp .chunk\n");
+    func.push_str(".failed:\n");
+    func.push_str("mov [esp + 256], eax\n");
+    func.push_str(".done:\n");
+    func.push_str("add esp, 256\n");
+    func.push_str("pop eax\n");
+    func.push_str("pop ebp\n");
+    func.push_str("pop edi\n");
+    func.push_str("pop esi\n");
+    func.push_str("pop edx\n");
+    func.push_str("pop ecx\n");
+    func.push_str("pop ebx\n");
+    func.push_str("ret\n");
+    func
+}
+
+// eax = descriptor. Returns 0, or a negative error code.
+pub fn close() -> String {
+    let mut func: String = String::new();
+    func.push_str("close:\n");
+    func.push_str("push ebx\n");
+    func.push_str("mov ebx, eax\n");
+    func.push_str("mov eax, 6\n");
+    func.push_str("int 0x80\n");
+    func.push_str("pop ebx\n");
+    func.push_str("ret\n");
+    func
+}
@@ -6,12 +6,21 @@ pub struct SymbolTableEntry {
     pub vtype: String,
     pub array_size: u32,
     level: u32,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionEntry {
+    pub name: String,
+    pub params: Vec<(String, String)>,
 }
 
 #[derive(Debug)]
 pub struct SymbolTable {
     pub table: Vec<SymbolTableEntry>,
     curr_level: u32,
+    pub functions: Vec<FunctionEntry>,
+    curr_function: Option<String>,
 }
 
 impl SymbolTable {
@@ -19,6 +28,8 @@ impl SymbolTable {
         SymbolTable {
             table: Vec::new(),
             curr_level: 0,
+            functions: Vec::new(),
+            curr_function: None,
         }
     }
 
@@ -28,9 +39,31 @@ impl SymbolTable {
             vtype: var_type,
             level: self.curr_level,
             array_size: array_size,
+            scope: self.curr_function.clone(),
         });
     }
 
+    pub fn add_function(&mut self, name: String, params: Vec<(String, String)>) {
+        self.functions.push(FunctionEntry { name, params });
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionEntry> {
+        self.functions.iter().rev().find(|func| func.name == name)
+    }
+
+    // Symbols added until `leave_function` are parameters and locals of `name`
+    pub fn enter_function(&mut self, name: String) {
+        self.curr_function = Some(name);
+    }
+
+    pub fn leave_function(&mut self) {
+        self.curr_function = None;
+    }
+
+    pub fn current_function(&self) -> Option<&str> {
+        self.curr_function.as_deref()
+    }
+
     pub fn reset_level(&mut self) {
         self.curr_level = 0;
     }
@@ -44,12 +77,37 @@ impl SymbolTable {
     }
 
     pub fn check_table(&self, var: String) -> Option<&SymbolTableEntry> {
-        for symbol in &self.table {
-            if symbol.vname == var && symbol.level <= self.curr_level {
-                return Some(symbol);
-            }
-        }
-        None
+        // Locals of the current function shadow globals
+        let visible = |symbol: &&SymbolTableEntry| symbol.vname == var && symbol.level <= self.curr_level;
+        self.table
+            .iter()
+            .filter(visible)
+            .find(|symbol| self.curr_function.is_some() && symbol.scope == self.curr_function)
+            .or_else(|| self.table.iter().filter(visible).find(|symbol| symbol.scope.is_none()))
+    }
+
+    // Like `check_table`, but for code generation where blocks are already
+    // resolved and only the enclosing function matters.
+    pub fn lookup(&self, var: &str, scope: Option<&str>) -> Option<&SymbolTableEntry> {
+        self.table
+            .iter()
+            .find(|symbol| symbol.vname == var && scope.is_some() && symbol.scope.as_deref() == scope)
+            .or_else(|| {
+                self.table
+                    .iter()
+                    .find(|symbol| symbol.vname == var && symbol.scope.is_none())
+            })
+    }
+
+    pub fn array_size(&self, arr: &str, scope: Option<&str>) -> Option<u32> {
+        self.lookup(arr, scope)
+            .filter(|symbol| symbol.vtype == "arr")
+            .map(|symbol| symbol.array_size)
+    }
+
+    pub fn is_pointer(&self, var: &str, scope: Option<&str>) -> bool {
+        self.lookup(var, scope)
+            .is_some_and(|symbol| symbol.vtype == "ptr")
     }
 
     pub fn print_table(&mut self) {
@@ -62,3 +120,13 @@ impl SymbolTable {
         }
     }
 }
+
+// Variables holding a function reference have the type `fn(N)`, N being the
+// number of arguments of the functions they can refer to.
+pub fn reference_type(arity: usize) -> String {
+    format!("fn({})", arity)
+}
+
+pub fn reference_arity(vtype: &str) -> Option<usize> {
+    vtype.strip_prefix("fn(")?.strip_suffix(')')?.parse().ok()
+}
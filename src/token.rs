@@ -6,7 +6,16 @@ pub enum TokenType {
     EndIf,
     While,
     EndWhile,
+    For,
+    To,
+    Step,
+    EndFor,
+    Do,
+    Until,
+    Break,
+    Continue,
     IntLiteral,
+    StringLiteral,
     AssignmentOperator,
     BinaryOperator,
     ComparisonOperator,
@@ -23,17 +32,99 @@ pub enum TokenType {
     Endfunc,
     Call,
     Print,
+    Write,
+    Exit,
+    Assert,
+    Asm,
     Error,
 }
 
+impl TokenType {
+    // How error messages refer to a token of this type
+    pub fn describe(self) -> &'static str {
+        match self {
+            TokenType::KeyWord => "'return'",
+            TokenType::Var => "'var'",
+            TokenType::If => "'if'",
+            TokenType::EndIf => "'endif'",
+            TokenType::While => "'while'",
+            TokenType::EndWhile => "'endwhile'",
+            TokenType::For => "'for'",
+            TokenType::To => "'to'",
+            TokenType::Step => "'step'",
+            TokenType::EndFor => "'endfor'",
+            TokenType::Do => "'do'",
+            TokenType::Until => "'until'",
+            TokenType::Break => "'break'",
+            TokenType::Continue => "'continue'",
+            TokenType::IntLiteral => "a number",
+            TokenType::StringLiteral => "a string",
+            TokenType::AssignmentOperator => "'='",
+            TokenType::BinaryOperator => "an operator",
+            TokenType::ComparisonOperator => "a comparison operator",
+            TokenType::NegationOperator => "'!'",
+            TokenType::SemiColon => "';'",
+            TokenType::Colon => "':'",
+            TokenType::Comma => "','",
+            TokenType::OpenBracket => "'('",
+            TokenType::CloseBracket => "')'",
+            TokenType::OpenArray => "'['",
+            TokenType::CloseArray => "']'",
+            TokenType::Variable => "a name",
+            TokenType::Func => "'func'",
+            TokenType::Endfunc => "'endfunc'",
+            TokenType::Call => "'call'",
+            TokenType::Print => "'print'",
+            TokenType::Write => "'write'",
+            TokenType::Exit => "'exit'",
+            TokenType::Assert => "'assert'",
+            TokenType::Asm => "an asm block",
+            TokenType::Error => "an invalid character",
+        }
+    }
+}
+
+// Where a token or node comes from: line and column (both from 1) of its
+// first character and its byte range in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // From the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end.max(self.end),
+            ..self
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub ttype: TokenType,
     pub value: Option<String>,
+    pub span: Span,
 }
 
 impl Token {
-    pub fn new(ttype: TokenType, value: Option<String>) -> Self {
-        Token{ ttype, value }
+    pub fn new(ttype: TokenType, value: Option<String>, span: Span) -> Self {
+        Token{ ttype, value, span }
+    }
+
+    // The token as written when that's short, otherwise what it is
+    pub fn describe(&self) -> String {
+        match (&self.value, self.ttype) {
+            (Some(value), TokenType::Variable | TokenType::IntLiteral)
+            | (Some(value), TokenType::BinaryOperator | TokenType::ComparisonOperator) => {
+                format!("'{}'", value)
+            }
+            (Some(value), TokenType::Error) => format!("an invalid character '{}'", value),
+            _ => self.ttype.describe().to_string(),
+        }
     }
 }
@@ -1,12 +1,71 @@
-use crate::token::{Token, TokenType};
+use crate::diagnostic::{self, Diagnostic};
+use crate::token::{Span, Token, TokenType};
 
 use std::iter::Peekable;
 use std::str::Chars;
 
+// Operators of two characters and what they stand for. `=<`, `=>` and `=!`
+// are older spellings of `<=`, `>=` and `!=`.
+const COMPARISON_OPERATORS: [(&str, &str); 7] = [
+    ("==", "=="),
+    ("!=", "!="),
+    ("<=", "<="),
+    (">=", ">="),
+    ("=<", "<="),
+    ("=>", ">="),
+    ("=!", "!="),
+];
+
+fn comparison_operator(first: char, second: char) -> Option<&'static str> {
+    let spelling: String = [first, second].iter().collect();
+    COMPARISON_OPERATORS
+        .iter()
+        .find(|(written, _)| *written == spelling)
+        .map(|(_, op)| *op)
+}
+
+// Magnitude of the smallest value, -2147483648
+pub const MIN_MAGNITUDE: u32 = 1 << 31;
+
+fn is_word_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+// Integer literal in decimal or with a `0x`, `0b` or `0o` prefix, `_` can
+// separate the digits as in `1_000_000`. Values are signed 32-bit. Decimal
+// ones go up to 2^31, which the parser only accepts after a unary minus, the
+// others are bit patterns so `0xffffffff` is -1.
+fn parse_int_literal(text: &str) -> Result<i64, String> {
+    let (radix, digits): (u32, &str) = match text.get(..2) {
+        Some("0x" | "0X") => (16, &text[2..]),
+        Some("0b" | "0B") => (2, &text[2..]),
+        Some("0o" | "0O") => (8, &text[2..]),
+        _ => (10, text),
+    };
+    let digits: String = digits.chars().filter(|ch| *ch != '_').collect();
+    if digits.is_empty() || !digits.chars().all(|ch| ch.is_digit(radix)) {
+        return Err(format!("Invalid number {}", text));
+    }
+
+    let value: u32 = u32::from_str_radix(&digits, radix)
+        .map_err(|_| format!("Number {} doesn't fit in 32 bits", text))?;
+    if radix != 10 {
+        return Ok(i64::from(value as i32));
+    }
+    if value > MIN_MAGNITUDE {
+        return Err(format!("Number {} is larger than {}", text, i32::MAX));
+    }
+    Ok(i64::from(value))
+}
+
 #[derive(Debug)]
 pub struct Tokenizer<'a> {
     _body: &'a str,
     iterator: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+    offset: usize,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Tokenizer<'a> {
@@ -14,6 +73,43 @@ impl<'a> Tokenizer<'a> {
         Tokenizer {
             _body: body,
             iterator: body.chars().peekable(),
+            line: 1,
+            column: 1,
+            offset: 0,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    // Consumes a character, keeping track of where in the source we are.
+    fn bump(&mut self) -> Option<char> {
+        let ch: char = self.iterator.next()?;
+        self.offset += ch.len_utf8();
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(ch)
+    }
+
+    fn position(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            start: self.offset,
+            end: self.offset,
+        }
+    }
+
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.offset,
+            ..start
         }
     }
 
@@ -23,26 +119,41 @@ impl<'a> Tokenizer<'a> {
 
     pub fn get_next_token(&mut self) -> Option<Token> {
         let mut idk: String = Default::default();
+        let word_start: Span = self.position();
 
         let stop_chars: Vec<char> = vec![
             ';', '(', ')', '=', '+', '-', '*', '/', '%', '^', '<', '>', '!', ',', ':', '[', ']',
+            '"',
         ];
 
+        // Names, keywords and numbers are made of ASCII letters, digits and `_`
         while let Some(ch) = self.iterator.peek() {
-            if (*ch).is_whitespace() || stop_chars.contains(ch) {
+            if !is_word_char(*ch) {
                 break;
             }
 
-            let test: char = self.iterator.next()?;
+            let test: char = self.bump()?;
             idk.push(test);
         }
+        let word_span: Span = self.span_from(word_start);
 
         while let Some(ch) = self.iterator.peek() {
             if (*ch).is_whitespace() {
-                self.iterator.next();
+                self.bump();
             } else if idk.is_empty() && stop_chars.contains(ch) {
                 let character: char = *ch;
-                self.iterator.next();
+                let start: Span = self.position();
+                self.bump();
+                if character == '"' {
+                    return Some(self.read_string(start));
+                }
+                // Longest match first, `<=` is one operator and not `<` then `=`
+                let next: Option<char> = self.iterator.peek().copied();
+                if let Some(op) = next.and_then(|next| comparison_operator(character, next)) {
+                    self.bump();
+                    let value: Option<String> = Some(op.to_string());
+                    return Some(Token::new(TokenType::ComparisonOperator, value, self.span_from(start)));
+                }
                 let (ttype, tvalue): (TokenType, Option<String>) = match character {
                     ';' => (TokenType::SemiColon, Some(';'.to_string())),
                     ',' => (TokenType::Comma, Some(','.to_string())),
@@ -59,7 +170,12 @@ impl<'a> Tokenizer<'a> {
                     '=' => (TokenType::AssignmentOperator, Some('='.to_string())),
                     _ => (TokenType::Error, None),
                 };
-                return Some(Token::new(ttype, tvalue));
+                return Some(Token::new(ttype, tvalue, self.span_from(start)));
+            } else if idk.is_empty() && !is_word_char(*ch) {
+                // Anything else is reported by the parser where it shows up
+                let start: Span = self.position();
+                let character: char = self.bump()?;
+                return Some(Token::new(TokenType::Error, Some(character.to_string()), self.span_from(start)));
             } else {
                 break;
             }
@@ -73,79 +189,134 @@ impl<'a> Tokenizer<'a> {
                 "endif" => (TokenType::EndIf, None),
                 "while" => (TokenType::While, None),
                 "endwhile" => (TokenType::EndWhile, None),
+                "for" => (TokenType::For, None),
+                "to" => (TokenType::To, None),
+                "step" => (TokenType::Step, None),
+                "endfor" => (TokenType::EndFor, None),
+                "do" => (TokenType::Do, None),
+                "until" => (TokenType::Until, None),
+                "break" => (TokenType::Break, None),
+                "continue" => (TokenType::Continue, None),
                 "func" => (TokenType::Func, None),
                 "endfunc" => (TokenType::Endfunc, None),
                 "call" => (TokenType::Call, None),
                 "print" => (TokenType::Print, None),
+                "write" => (TokenType::Write, None),
+                "exit" => (TokenType::Exit, None),
+                "assert" => (TokenType::Assert, None),
+                "asm" => return Some(self.read_asm(word_start)),
                 _ => (TokenType::Error, None),
             };
 
             if ttype == TokenType::Error {
-                if idk.as_str().parse::<u32>().is_ok() {
+                if idk.starts_with(|ch: char| ch.is_ascii_digit()) {
+                    // Kept in decimal. A bad number keeps its text so that
+                    // parsing goes on and later messages quote what was written
                     ttype = TokenType::IntLiteral;
-                    tvalue = Some(idk.clone());
+                    tvalue = match parse_int_literal(&idk) {
+                        Ok(value) => Some(value.to_string()),
+                        Err(message) => {
+                            let error: Diagnostic =
+                                Diagnostic::error(diagnostic::INVALID_LITERAL, message, word_span);
+                            self.diagnostics.push(error);
+                            Some(idk.clone())
+                        }
+                    };
                 } else {
                     ttype = TokenType::Variable;
                     tvalue = Some(idk.clone());
                 }
             }
 
-            return Some(Token::new(ttype, tvalue));
+            Some(Token::new(ttype, tvalue, word_span))
         } else {
-            return None;
+            None
+        }
+    }
+
+    // Reads the rest of a string literal, the opening quote is already
+    // consumed. A string left open stops at the end of its line.
+    fn read_string(&mut self, start: Span) -> Token {
+        let mut value: String = String::new();
+
+        loop {
+            match self.iterator.peek().copied() {
+                None | Some('\n') => {
+                    let span: Span = self.span_from(start);
+                    let message: &str = "Unterminated string literal";
+                    self.diagnostics.push(Diagnostic::error(diagnostic::UNTERMINATED, message, span));
+                    return Token::new(TokenType::StringLiteral, Some(value), span);
+                }
+                Some('"') => {
+                    self.bump();
+                    return Token::new(TokenType::StringLiteral, Some(value), self.span_from(start));
+                }
+                Some('\\') => {
+                    let escape: Span = self.position();
+                    self.bump();
+                    let escaped: char = match self.iterator.peek().copied() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('0') => '\0',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        // Reported as unterminated on the next round
+                        None | Some('\n') => continue,
+                        Some(other) => {
+                            self.bump();
+                            let message: String = format!("Unknown escape \\{}", other);
+                            let span: Span = self.span_from(escape);
+                            self.diagnostics.push(Diagnostic::error(diagnostic::UNKNOWN_ESCAPE, message, span));
+                            continue;
+                        }
+                    };
+                    self.bump();
+                    value.push(escaped);
+                }
+                Some(ch) => {
+                    self.bump();
+                    value.push(ch);
+                }
+            }
         }
     }
-    // pub fn fix_comparison_operators(tokens: &mut Vec<Token>) {
-    //     for index in 0..tokens.len() - 1 {
-    //         if tokens[index].ttype == TokenType::AssignmentOperator {
-    //             if index + 1 < tokens.len()
-    //                 && (tokens[index + 1].ttype == TokenType::ComparisonOperator
-    //                     || tokens[index + 1].ttype == TokenType::AssignmentOperator)
-    //             {
-    //                 let mut aux: String = String::new();
-
-    //                 if let Some(val) = tokens[index].value.as_ref() {
-    //                     aux.push_str(val);
-    //                 }
-    //                 if let Some(val) = tokens[index + 1].value.as_ref() {
-    //                     aux.push_str(val);
-    //                 }
-
-    //                 tokens[index].value = Some(aux);
-    //                 tokens[index].ttype = TokenType::ComparisonOperator;
-
-    //                 tokens.remove(index + 1);
-    //             }
-    //         }
-    //     }
-    // }
-
-    pub fn fix_comparison_operators(tokens: &mut Vec<Token>) {
-        let mut index = 0;
-        while index + 1 < tokens.len() {
-            if tokens[index].ttype == TokenType::AssignmentOperator {
-                if tokens[index + 1].ttype == TokenType::ComparisonOperator
-                    || tokens[index + 1].ttype == TokenType::NegationOperator
-                    || tokens[index + 1].ttype == TokenType::AssignmentOperator
-                {
-                    let mut aux = String::new();
-
-                    if let Some(val) = tokens[index].value.as_ref() {
-                        aux.push_str(val);
-                    }
-                    if let Some(val) = tokens[index + 1].value.as_ref() {
-                        aux.push_str(val);
-                    }
 
-                    tokens[index].value = Some(aux);
-                    tokens[index].ttype = TokenType::ComparisonOperator;
+    // Reads the raw lines of an `asm; ... endasm;` block, `asm` is already
+    // consumed. The `;` after `endasm` is left for the next token.
+    fn read_asm(&mut self, start: Span) -> Token {
+        let keyword: Span = self.span_from(start);
+        if self.iterator.peek() == Some(&';') {
+            self.bump();
+        } else {
+            let message: &str = "expected ';' after 'asm'";
+            self.diagnostics.push(Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, message, keyword));
+        }
 
-                    tokens.remove(index + 1);
-                    // Don't increment index, since the next token has shifted into the current + 1 position
-                    continue;
+        let mut lines: Vec<String> = Vec::new();
+        while !self.is_done() {
+            let rest: String = self.iterator.clone().take_while(|ch| *ch != '\n').collect();
+            let trimmed: &str = rest.trim_start();
+            if let Some(after) = trimmed.strip_prefix("endasm") {
+                if after.trim_start().starts_with(';') {
+                    for _ in 0..rest.len() - after.len() {
+                        self.bump();
+                    }
+                    let span: Span = self.span_from(start);
+                    return Token::new(TokenType::Asm, Some(lines.join("\n")), span);
                 }
             }
-            index += 1;
+
+            for _ in 0..rest.chars().count() {
+                self.bump();
+            }
+            self.bump();
+            if !trimmed.trim_end().is_empty() {
+                lines.push(trimmed.trim_end().to_string());
+            }
         }
+
+        let message: &str = "asm block without endasm;";
+        self.diagnostics.push(Diagnostic::error(diagnostic::UNTERMINATED, message, keyword));
+        Token::new(TokenType::Asm, Some(lines.join("\n")), self.span_from(start))
     }
 }